@@ -0,0 +1,4 @@
+pub mod apu;
+pub mod dmc;
+pub mod frame_counter;
+pub mod resampler;
@@ -0,0 +1,164 @@
+use crate::apu_emu::dmc::Dmc;
+use crate::apu_emu::frame_counter::FrameCounter;
+use crate::apu_emu::resampler::{BufferStrategy, Resampler, DEFAULT_OUTPUT_RATE, NTSC_CPU_CLOCK};
+use crate::savestate::{StateReader, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+pub struct Apu {
+    pub dmc: Dmc,
+    pub frame_counter: FrameCounter,
+    resampler: Resampler,
+    // デバッグ用のミュート/ソロ。$4015とは別で、ミキサーに入れるかどうかだけ
+    channel_enabled: [bool; 5],
+    solo: Option<Channel>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            resampler: Resampler::new(NTSC_CPU_CLOCK, DEFAULT_OUTPUT_RATE),
+            channel_enabled: [true; 5],
+            solo: None,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4010 => self.dmc.write_control(data),
+            0x4011 => self.dmc.write_direct_load(data),
+            0x4012 => self.dmc.write_sample_address(data),
+            0x4013 => self.dmc.write_sample_length(data),
+            0x4015 => self.dmc.set_enabled(data & 0b0001_0000 != 0),
+            0x4017 => self.frame_counter.write(data),
+            _ => {
+                // 他のチャンネルは未実装
+            }
+        }
+    }
+
+    // $4015
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.dmc.is_active() {
+            status |= 0b0001_0000;
+        }
+        if self.frame_counter.irq {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq {
+            status |= 0b1000_0000;
+        }
+        // 読むとフレームIRQは消える
+        self.frame_counter.irq = false;
+        status
+    }
+
+    // CPUの1サイクル分
+    pub fn tick(&mut self) {
+        self.dmc.tick();
+        // エンベロープや長さカウンタを持つチャンネルはまだないので、クロックは使い道がない
+        self.frame_counter.tick();
+        self.resampler.push(self.output());
+    }
+
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.channel_enabled[channel as usize] = enabled;
+    }
+
+    // Someのあいだはそのチャンネルだけを鳴らす
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.solo = channel;
+    }
+
+    fn is_audible(&self, channel: Channel) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => self.channel_enabled[channel as usize],
+        }
+    }
+
+    // ミキサーの出力 (0.0〜1.0)。線形近似の係数はnesdev wikiのもの
+    // https://www.nesdev.org/wiki/APU_Mixer
+    // パルス・三角波・ノイズは未実装なのでDMCだけ
+    pub fn output(&self) -> f32 {
+        let dmc = if self.is_audible(Channel::Dmc) { self.dmc.output() } else { 0 };
+        0.00335 * dmc as f32
+    }
+
+    // 前回取り出してからの出力サンプル (DEFAULT_OUTPUT_RATE Hz)
+    pub fn drain_samples(&mut self) -> Vec<f32> {
+        self.resampler.drain()
+    }
+
+    // 取り出されずに溜まるサンプルの上限と、溢れたときの扱い
+    // 既定はDEFAULT_BUFFER_FRAMES分でDropOldest。Noneを渡すと上限なしになる
+    pub fn set_sample_buffer(&mut self, capacity: Option<usize>, strategy: BufferStrategy) {
+        self.resampler.set_buffer(capacity, strategy);
+    }
+
+    // 溜まっているサンプル数。フロントエンドはこれを見て実行速度を調整できる
+    pub fn buffered_samples(&self) -> usize {
+        self.resampler.fill_level()
+    }
+
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill_sample_buffer(data);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.dmc.save_state(w);
+        self.frame_counter.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.dmc.load_state(r)?;
+        self.frame_counter.load_state(r)
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4011, 0x40);
+        let dmc = 0.00335 * 0x40 as f32;
+        assert_eq!(apu.output(), dmc);
+
+        apu.set_channel_enabled(Channel::Triangle, false);
+        assert_eq!(apu.output(), dmc);
+
+        apu.set_channel_enabled(Channel::Dmc, false);
+        assert_eq!(apu.output(), 0.0);
+        // $4015とは別なのでDMC自体は動いている
+        assert_eq!(apu.dmc.output(), 0x40);
+
+        apu.set_channel_enabled(Channel::Dmc, true);
+        apu.set_solo(Some(Channel::Triangle));
+        assert_eq!(apu.output(), 0.0);
+        apu.set_solo(Some(Channel::Dmc));
+        assert_eq!(apu.output(), dmc);
+    }
+}
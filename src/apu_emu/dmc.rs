@@ -0,0 +1,272 @@
+use crate::savestate::{StateReader, StateWriter};
+
+// NTSCのCPUサイクル数
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+pub struct Dmc {
+    irq_enabled: bool,
+    loop_flag: bool,
+    rate: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            loop_flag: false,
+            rate: RATE_TABLE[0],
+            timer: RATE_TABLE[0],
+            output_level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    // $4010
+    pub fn write_control(&mut self, data: u8) {
+        self.irq_enabled = data & 0b1000_0000 != 0;
+        self.loop_flag = data & 0b0100_0000 != 0;
+        self.rate = RATE_TABLE[(data & 0b1111) as usize];
+        if !self.irq_enabled {
+            self.irq = false;
+        }
+    }
+
+    // $4011
+    pub fn write_direct_load(&mut self, data: u8) {
+        self.output_level = data & 0b0111_1111;
+    }
+
+    // $4012
+    pub fn write_sample_address(&mut self, data: u8) {
+        self.sample_address = 0xc000 + data as u16 * 64;
+    }
+
+    // $4013
+    pub fn write_sample_length(&mut self, data: u8) {
+        self.sample_length = data as u16 * 16 + 1;
+    }
+
+    // $4015のbit4
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    // サンプルバッファが空で読み残しがあれば、DMAで読むアドレスを返す
+    pub fn fetch_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_sample_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_address = if self.current_address == 0xffff {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // CPUの1サイクル分
+    pub fn tick(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.rate;
+
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.loop_flag);
+        w.write_u16(self.rate);
+        w.write_u16(self.timer);
+        w.write_u8(self.output_level);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_address);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+        w.write_u8(self.shift_register);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+        w.write_bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.rate = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.output_level = r.read_u8()?;
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let has_sample = r.read_bool()?;
+        let sample = r.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift_register = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        self.silence = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Dmc::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_address_and_length() {
+        let mut dmc = Dmc::new();
+        dmc.write_sample_address(0x02);
+        dmc.write_sample_length(0x01);
+        dmc.set_enabled(true);
+
+        assert!(dmc.is_active());
+        for i in 0..17 {
+            assert_eq!(dmc.fetch_address(), Some(0xc080 + i));
+            dmc.fill_sample_buffer(0);
+            // バッファが埋まっている間は次を読まない
+            assert_eq!(dmc.fetch_address(), None);
+            dmc.sample_buffer = None;
+        }
+        assert!(!dmc.is_active());
+        assert_eq!(dmc.fetch_address(), None);
+    }
+
+    #[test]
+    fn test_output_level_follows_bits() {
+        let mut dmc = Dmc::new();
+        dmc.write_control(0x0f);
+        dmc.write_direct_load(0x40);
+        dmc.write_sample_length(0);
+        dmc.set_enabled(true);
+        dmc.fill_sample_buffer(0b0000_0011);
+
+        // 最初の出力サイクルが終わるまでは無音 (タイマーは初期値428から数え始める)
+        for _ in 0..(428 + 54 * 7) {
+            dmc.tick();
+        }
+        assert_eq!(dmc.output(), 0x40);
+
+        for _ in 0..54 {
+            dmc.tick();
+        }
+        assert_eq!(dmc.output(), 0x42);
+        for _ in 0..54 {
+            dmc.tick();
+        }
+        assert_eq!(dmc.output(), 0x44);
+        for _ in 0..54 {
+            dmc.tick();
+        }
+        assert_eq!(dmc.output(), 0x42);
+    }
+
+    #[test]
+    fn test_irq_at_end_of_sample() {
+        let mut dmc = Dmc::new();
+        dmc.write_control(0b1000_0000);
+        dmc.write_sample_length(0);
+        dmc.set_enabled(true);
+        dmc.fill_sample_buffer(0);
+
+        assert!(dmc.irq);
+        dmc.set_enabled(false);
+        assert!(!dmc.irq);
+    }
+
+    #[test]
+    fn test_loop_restarts() {
+        let mut dmc = Dmc::new();
+        dmc.write_control(0b0100_0000);
+        dmc.write_sample_length(0);
+        dmc.set_enabled(true);
+        dmc.fill_sample_buffer(0);
+
+        assert!(dmc.is_active());
+        assert!(!dmc.irq);
+    }
+}
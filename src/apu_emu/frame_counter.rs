@@ -0,0 +1,176 @@
+use crate::savestate::{StateReader, StateWriter};
+
+// フレームシーケンサが各ステップを出すCPUサイクル (NTSC)
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+const STEP_CYCLES: [u32; 4] = [7457, 14913, 22371, 29829];
+const FOUR_STEP_PERIOD: u32 = 29830;
+const FIVE_STEP_LAST: u32 = 37281;
+const FIVE_STEP_PERIOD: u32 = 37282;
+
+// 1サイクルでエンベロープ(4分の1フレーム)と長さカウンタ(2分の1フレーム)を進めるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameClock {
+    pub quarter: bool,
+    pub half: bool,
+}
+
+impl FrameClock {
+    const QUARTER: FrameClock = FrameClock { quarter: true, half: false };
+    const BOTH: FrameClock = FrameClock { quarter: true, half: true };
+}
+
+pub struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    cycle: u32,
+    // $4017に書いてからリセットされるまでの残りサイクル
+    reset_delay: u8,
+    pending_mode: u8,
+    odd_cycle: bool,
+    pub irq: bool,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        FrameCounter {
+            five_step: false,
+            irq_inhibit: false,
+            cycle: 0,
+            reset_delay: 0,
+            pending_mode: 0,
+            odd_cycle: false,
+            irq: false,
+        }
+    }
+
+    // $4017。bit7で5ステップ、bit6でIRQ禁止
+    pub fn write(&mut self, data: u8) {
+        self.irq_inhibit = data & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.irq = false;
+        }
+        // シーケンサのリセットはAPUサイクルの途中なら3、間なら4CPUサイクル後
+        self.pending_mode = data;
+        self.reset_delay = if self.odd_cycle { 4 } else { 3 };
+    }
+
+    // CPUの1サイクル分
+    pub fn tick(&mut self) -> FrameClock {
+        self.odd_cycle = !self.odd_cycle;
+
+        if self.reset_delay > 0 {
+            self.reset_delay -= 1;
+            if self.reset_delay == 0 {
+                self.five_step = self.pending_mode & 0b1000_0000 != 0;
+                self.cycle = 0;
+                // 5ステップにするとその場で両方を1回進める
+                return if self.five_step { FrameClock::BOTH } else { FrameClock::default() };
+            }
+        }
+
+        self.cycle += 1;
+        let clock = match self.cycle {
+            c if c == STEP_CYCLES[0] || c == STEP_CYCLES[2] => FrameClock::QUARTER,
+            c if c == STEP_CYCLES[1] => FrameClock::BOTH,
+            c if c == STEP_CYCLES[3] && !self.five_step => FrameClock::BOTH,
+            FIVE_STEP_LAST if self.five_step => FrameClock::BOTH,
+            _ => FrameClock::default(),
+        };
+
+        if !self.five_step && !self.irq_inhibit && (STEP_CYCLES[3] - 1..=FOUR_STEP_PERIOD).contains(&self.cycle) {
+            self.irq = true;
+        }
+
+        let period = if self.five_step { FIVE_STEP_PERIOD } else { FOUR_STEP_PERIOD };
+        if self.cycle >= period {
+            self.cycle = 0;
+        }
+        clock
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.five_step);
+        w.write_bool(self.irq_inhibit);
+        w.write_u16(self.cycle as u16);
+        w.write_u8(self.reset_delay);
+        w.write_u8(self.pending_mode);
+        w.write_bool(self.odd_cycle);
+        w.write_bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.five_step = r.read_bool()?;
+        self.irq_inhibit = r.read_bool()?;
+        self.cycle = r.read_u16()? as u32;
+        self.reset_delay = r.read_u8()?;
+        self.pending_mode = r.read_u8()?;
+        self.odd_cycle = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        FrameCounter::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 各クロックが出たサイクル (書き込んだ直後の1サイクル目を1とする)
+    fn clocks(counter: &mut FrameCounter, cycles: u32) -> (Vec<u32>, Vec<u32>) {
+        let mut quarter = Vec::new();
+        let mut half = Vec::new();
+        for cycle in 1..=cycles {
+            let clock = counter.tick();
+            if clock.quarter {
+                quarter.push(cycle);
+            }
+            if clock.half {
+                half.push(cycle);
+            }
+        }
+        (quarter, half)
+    }
+
+    #[test]
+    fn test_five_step_schedule() {
+        let mut counter = FrameCounter::new();
+        counter.write(0b1100_0000);
+
+        let (quarter, half) = clocks(&mut counter, 3 + FIVE_STEP_PERIOD * 2);
+        // 3サイクル後のリセットで1回、そのあとは37282サイクルごとに4回と2回
+        let mut expected_quarter = vec![3];
+        let mut expected_half = vec![3];
+        for frame in 0..2 {
+            let base = 3 + frame * FIVE_STEP_PERIOD;
+            expected_quarter.extend([7457, 14913, 22371, 37281].map(|c| base + c));
+            expected_half.extend([14913, 37281].map(|c| base + c));
+        }
+        assert_eq!(quarter, expected_quarter);
+        assert_eq!(half, expected_half);
+        assert!(!counter.irq);
+    }
+
+    #[test]
+    fn test_four_step_schedule_and_irq() {
+        let mut counter = FrameCounter::new();
+        // 奇数サイクルの後に書くとリセットは4サイクル後
+        counter.tick();
+        counter.write(0);
+
+        let (quarter, half) = clocks(&mut counter, 4 + FOUR_STEP_PERIOD);
+        assert_eq!(quarter, [7457, 14913, 22371, 29829].map(|c| 4 + c));
+        assert_eq!(half, [14913, 29829].map(|c| 4 + c));
+        assert!(counter.irq);
+
+        // IRQ禁止にすると立っていたフラグも消える
+        counter.write(0b0100_0000);
+        assert!(!counter.irq);
+        clocks(&mut counter, 3 + FOUR_STEP_PERIOD);
+        assert!(!counter.irq);
+    }
+}
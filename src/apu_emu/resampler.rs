@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+
+// APUはCPUクロック(約1.79MHz)で1サンプルずつ出力するので、
+// SDL2に渡す前に出力レートまで間引く
+pub const NTSC_CPU_CLOCK: f64 = 1_789_773.0;
+pub const DEFAULT_OUTPUT_RATE: u32 = 44_100;
+// 既定の出力バッファの上限 (フレーム数)。誰も取り出さなくてもメモリが伸び続けないようにする
+pub const DEFAULT_BUFFER_FRAMES: u32 = 4;
+
+// 出力バッファが上限に達したときの扱い
+// フロントエンドの消費が遅いとバッファが伸び続け、遅延やノイズの原因になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BufferStrategy {
+    // 新しいサンプルを捨てる。フロントエンドはfill_levelを見てエミュレーションを待たせる
+    Block,
+    // 古いサンプルから捨てる。遅延は上限で頭打ちになる
+    #[default]
+    DropOldest,
+    // 溢れたら半分まで一気に捨てて遅延を戻す。捨てる回数が少ないのでプチノイズも少ない
+    Resync,
+}
+
+pub struct Resampler {
+    output_rate: u32,
+    // 出力1サンプルあたりの入力サンプル数
+    step: f64,
+    phase: f64,
+    acc: f32,
+    acc_count: u32,
+    output: VecDeque<f32>,
+    // Noneなら上限なし (set_bufferで明示したときだけ)
+    capacity: Option<usize>,
+    strategy: BufferStrategy,
+}
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: u32) -> Self {
+        Resampler {
+            output_rate,
+            step: input_rate / output_rate as f64,
+            phase: 0.0,
+            acc: 0.0,
+            acc_count: 0,
+            output: VecDeque::new(),
+            capacity: Some((output_rate * DEFAULT_BUFFER_FRAMES / 60) as usize),
+            strategy: BufferStrategy::default(),
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        // 区間内の入力を平均する (box filter) ことでローパスをかけてから間引く
+        self.acc += sample;
+        self.acc_count += 1;
+        self.phase += 1.0;
+
+        if self.phase >= self.step {
+            self.phase -= self.step;
+            self.push_output(self.acc / self.acc_count as f32);
+            self.acc = 0.0;
+            self.acc_count = 0;
+        }
+    }
+
+    fn push_output(&mut self, sample: f32) {
+        if let Some(capacity) = self.capacity {
+            if self.output.len() >= capacity {
+                match self.strategy {
+                    BufferStrategy::Block => return,
+                    BufferStrategy::DropOldest => {
+                        self.output.pop_front();
+                    }
+                    BufferStrategy::Resync => {
+                        self.output.drain(..self.output.len() - capacity / 2);
+                    }
+                }
+            }
+        }
+        self.output.push_back(sample);
+    }
+
+    pub fn set_buffer(&mut self, capacity: Option<usize>, strategy: BufferStrategy) {
+        self.capacity = capacity;
+        self.strategy = strategy;
+        if let Some(capacity) = capacity {
+            if self.output.len() > capacity {
+                self.output.drain(..self.output.len() - capacity);
+            }
+        }
+    }
+
+    // まだ取り出されていないサンプル数
+    pub fn fill_level(&self) -> usize {
+        self.output.len()
+    }
+
+    pub fn drain(&mut self) -> Vec<f32> {
+        self.output.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_constant_input_one_second() {
+        let mut resampler = Resampler::new(NTSC_CPU_CLOCK, DEFAULT_OUTPUT_RATE);
+        resampler.set_buffer(None, BufferStrategy::default());
+        for _ in 0..NTSC_CPU_CLOCK as usize {
+            resampler.push(0.5);
+        }
+
+        let samples = resampler.drain();
+        let len = samples.len() as i64;
+        assert!((len - DEFAULT_OUTPUT_RATE as i64).abs() <= 1, "got {} samples", len);
+        assert!(samples.iter().all(|s| (s - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_drain_empties_buffer() {
+        let mut resampler = Resampler::new(NTSC_CPU_CLOCK, 48_000);
+        for _ in 0..(NTSC_CPU_CLOCK / 60.0).ceil() as usize {
+            resampler.push(1.0);
+        }
+
+        let samples = resampler.drain();
+        assert_eq!(samples.len(), 800);
+        assert_eq!(resampler.fill_level(), 0);
+    }
+
+    #[test]
+    fn test_default_buffer_is_bounded() {
+        let mut resampler = Resampler::new(NTSC_CPU_CLOCK, DEFAULT_OUTPUT_RATE);
+        for _ in 0..NTSC_CPU_CLOCK as usize {
+            resampler.push(0.5);
+        }
+
+        // 1秒分を取り出さずに溜めても、上限の4フレーム分しか残らない
+        assert_eq!(resampler.fill_level(), 2940);
+    }
+
+    // 入力1サンプルごとに出力1サンプル。値は通し番号
+    fn push_numbered(resampler: &mut Resampler, count: usize) {
+        for i in 0..count {
+            resampler.push(i as f32);
+        }
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut resampler = Resampler::new(1.0, 1);
+        resampler.set_buffer(Some(100), BufferStrategy::DropOldest);
+        push_numbered(&mut resampler, 250);
+
+        assert_eq!(resampler.fill_level(), 100);
+        let samples = resampler.drain();
+        assert_eq!(samples.first(), Some(&150.0));
+        assert_eq!(samples.last(), Some(&249.0));
+    }
+
+    #[test]
+    fn test_block_and_resync() {
+        let mut resampler = Resampler::new(1.0, 1);
+        resampler.set_buffer(Some(100), BufferStrategy::Block);
+        push_numbered(&mut resampler, 250);
+        let samples = resampler.drain();
+        assert_eq!((samples.len(), samples[99]), (100, 99.0));
+
+        resampler.set_buffer(Some(100), BufferStrategy::Resync);
+        push_numbered(&mut resampler, 101);
+        assert_eq!(resampler.fill_level(), 51);
+        assert_eq!(resampler.drain()[0], 50.0);
+    }
+}
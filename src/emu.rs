@@ -4,3 +4,8 @@ pub mod bus;
 pub mod cartridge;
 pub mod trace;
 pub mod interrupt;
+pub mod mapper;
+pub mod profiler;
+pub mod game_genie;
+#[cfg(test)]
+pub mod harness;
@@ -1,66 +1,410 @@
-use crate::emu::cpu::Mem;
-use crate::emu::cartridge::Rom;
-use crate::ppu_emu::ppu::{NesPPU, PPU};
+use crate::apu_emu::apu::Apu;
+use crate::emu::cpu::{Mem, SystemBus};
+use crate::emu::cartridge::{Rom, RomError};
+use crate::emu::game_genie::GameGenie;
+use crate::emu::mapper::{self, SharedMapper};
+use crate::ppu_emu::ppu::{NesPPU, PpuTiming, PPU};
 use crate::joypad::Joypad;
+use crate::savestate::{StateReader, StateWriter};
+use std::collections::VecDeque;
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1fff;
 //const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3fff;
 
+// 電源投入時のRAMの中身
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowerOnState {
+    #[default]
+    Zeroed,
+    Filled(u8),
+    // $00が4バイト、$FFが4バイトの繰り返し
+    Pattern,
+}
+
+impl PowerOnState {
+    fn init_ram(&self, ram: &mut [u8]) {
+        for (i, byte) in ram.iter_mut().enumerate() {
+            *byte = match self {
+                PowerOnState::Zeroed => 0,
+                PowerOnState::Filled(value) => *value,
+                PowerOnState::Pattern => if i & 0b100 == 0 { 0x00 } else { 0xff },
+            };
+        }
+    }
+}
+
+// 何も繋がっていないアドレスへのアクセス。読み込みのdataは返した値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmappedAccess {
+    pub addr: u16,
+    pub is_write: bool,
+    pub data: u8,
+}
+
+// CPUのアドレスが何に繋がっているか (デバッガのメモリビュー用)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemRegion {
+    // 2KBの内蔵RAM。offsetはミラーを外したアドレス
+    Ram { offset: u16 },
+    // $2000-$2007のどれか。ミラーは外してある
+    PpuRegister(u16),
+    Apu,
+    OamDma,
+    // 1か2。$4017への書き込みはAPUのフレームカウンタに行く
+    Controller(u8),
+    PrgRam { offset: u16 },
+    // offsetは今バンクに入っているPRG-ROMの位置
+    PrgRom { offset: Option<usize> },
+    Unmapped,
+}
+
+const UNMAPPED_LOG_CAPACITY: usize = 256;
+
+// NMIごとに呼ばれる。最後の引数はこのフレームのAPUの出力サンプル
+// (vblankの途中でNMIを有効にしたときなど、vblankの開始と重ならないNMIでは空)
+pub type FrameCallback<'call> = Box<dyn FnMut(&NesPPU, &mut Joypad, &[f32]) + 'call>;
+
+// NMIが無効でもvblankの開始ごとに呼ばれる。最後の引数は前のvblankからのAPUの出力サンプル
+pub type EndOfFrameCallback<'call> = Box<dyn FnMut(&NesPPU, &mut Joypad, &[f32]) + 'call>;
+
+// $8000-$FFFFへの書き込み (マッパーのレジスタ) ごとに呼ばれる。引数はアドレスと値
+pub type MapperWriteHook<'call> = Box<dyn FnMut(u16, u8) + 'call>;
+
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    rom: Vec<u8>,
+    prg_ram: [u8; 0x2000],
+    mapper: SharedMapper,
     ppu: NesPPU,
+    apu: Apu,
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
+    gameloop_callback: FrameCallback<'call>,
+    end_of_frame_callback: Option<EndOfFrameCallback<'call>>,
+    // vblankの開始ごとにAPUから取り出したサンプル。次のvblankで置き換わるので溜まり続けない
+    frame_samples: Vec<f32>,
+    mapper_write_hook: Option<MapperWriteHook<'call>>,
     joypad1: Joypad,
+    joypad2: Joypad,
+    frame_complete: bool,
+    // DMAでCPUを止める残りサイクル。CPUが次の命令の前に消化する
+    halt_cycles: usize,
+    // request_nmiで立てたNMI。PPUのNMIと同時なら1回にまとまる
+    nmi_requested: bool,
+    strict: bool,
+    // 有効にしたときだけ、最新UNMAPPED_LOG_CAPACITY件を残す
+    unmapped_log: Option<VecDeque<UnmappedAccess>>,
+    cheats: Vec<GameGenie>,
+    // CPUのデータバスに最後に乗った値。何も出力しないビットはこれが読める
+    open_bus: u8,
 }
 
 impl<'a> Bus<'a> {
-    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
+    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Result<Bus<'call>, RomError>
+    where
+        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+    {
+        Bus::with_power_on_state(rom, PowerOnState::default(), gameloop_callback)
+    }
+
+    pub fn with_power_on_state<'call, F>(rom: Rom, power_on: PowerOnState, gameloop_callback: F) -> Result<Bus<'call>, RomError>
+    where
+        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+    {
+        Ok(Bus::with_mapper(mapper::from_rom(rom)?, power_on, gameloop_callback))
+    }
+
+    // 自前のマッパーをつなぐ (テストや未対応のマッパー用)
+    pub fn with_mapper<'call, F>(mapper: SharedMapper, power_on: PowerOnState, mut gameloop_callback: F) -> Bus<'call>
     where
         F: FnMut(&NesPPU, &mut Joypad) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        Bus::with_frame_callback(
+            mapper,
+            power_on,
+            Box::new(move |ppu: &NesPPU, joypad: &mut Joypad, _samples: &[f32]| gameloop_callback(ppu, joypad)),
+        )
+    }
+
+    // 音も鳴らすフロントエンド用。映像と同じvblankでサンプルを受け取れる
+    pub fn with_audio_callback<'call, F>(rom: Rom, gameloop_callback: F) -> Result<Bus<'call>, RomError>
+    where
+        F: FnMut(&NesPPU, &mut Joypad, &[f32]) + 'call,
+    {
+        Ok(Bus::with_frame_callback(mapper::from_rom(rom)?, PowerOnState::default(), Box::new(gameloop_callback)))
+    }
+
+    // ラスター効果のタイミングを自前で扱うフロントエンド用。呼ばれたときのスキャンラインなどを受け取る
+    pub fn with_timing_callback<'call, F>(rom: Rom, mut gameloop_callback: F) -> Result<Bus<'call>, RomError>
+    where
+        F: FnMut(&NesPPU, &mut Joypad, PpuTiming) + 'call,
+    {
+        Ok(Bus::with_frame_callback(
+            mapper::from_rom(rom)?,
+            PowerOnState::default(),
+            Box::new(move |ppu: &NesPPU, joypad: &mut Joypad, _samples: &[f32]| {
+                gameloop_callback(ppu, joypad, ppu.timing())
+            }),
+        ))
+    }
+
+    fn with_frame_callback(mapper: SharedMapper, power_on: PowerOnState, gameloop_callback: FrameCallback<'a>) -> Bus<'a> {
+        let ppu = NesPPU::with_mapper(mapper.clone());
+        let mut cpu_vram = [0; 2048];
+        power_on.init_ram(&mut cpu_vram);
         Bus {
-            cpu_vram: [0; 2048],
-            rom: rom.prg_rom,
+            cpu_vram,
+            prg_ram: [0; 0x2000],
+            mapper,
             ppu,
+            apu: Apu::new(),
             cycles: 0,
-            gameloop_callback: Box::from(gameloop_callback),
+            gameloop_callback,
+            end_of_frame_callback: None,
+            frame_samples: Vec::new(),
+            mapper_write_hook: None,
             joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            frame_complete: false,
+            halt_cycles: 0,
+            nmi_requested: false,
+            strict: false,
+            unmapped_log: None,
+            cheats: Vec::new(),
+            open_bus: 0,
         }
     }
 
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
+        for _ in 0..cycles {
+            self.apu.tick();
+        }
 
         let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
+        if self.ppu.tick(cycles * 3) {
+            self.frame_complete = true;
+        }
         let nmi_after = self.ppu.nmi_interrupt.is_some();
 
+        // NMIを使わないゲームやコールバックのないフロントエンドでもサンプルが溜まらないよう、
+        // NMIではなくvblankの開始で取り出す
+        let vblank_started = self.ppu.poll_vblank_started();
+        if vblank_started {
+            self.frame_samples = self.apu.drain_samples();
+        }
         if !nmi_before && nmi_after {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+            let samples: &[f32] = if vblank_started { &self.frame_samples } else { &[] };
+            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, samples);
+        }
+        if vblank_started {
+            if let Some(callback) = &mut self.end_of_frame_callback {
+                callback(&self.ppu, &mut self.joypad1, &self.frame_samples);
+            }
+        }
+
+        if let Some(addr) = self.apu.dmc_fetch_address() {
+            let data = self.mem_read(addr);
+            self.apu.dmc_fill(data);
+            // DMCのDMAの間CPUは4サイクル止まる
+            self.tick(4);
+        }
+    }
+
+    // CPUを指定したサイクル数だけ止める (2A03のRDY線)
+    pub fn halt(&mut self, cycles: usize) {
+        self.halt_cycles += cycles;
+    }
+
+    // strictだと$2002やROM領域への書き込みでpanicする (デバッグ用)
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn set_unmapped_access_log_enabled(&mut self, enabled: bool) {
+        self.unmapped_log = if enabled { Some(VecDeque::with_capacity(UNMAPPED_LOG_CAPACITY)) } else { None };
+    }
+
+    // 古い順。無効のときは空
+    pub fn unmapped_accesses(&self) -> Vec<UnmappedAccess> {
+        self.unmapped_log.iter().flatten().copied().collect()
+    }
+
+    // $4016/$4017の読み出し。bit0がコントローラ、bit1-4は0 (拡張端子なし)、bit5-7はオープンバス
+    // 普通は直前に読んだオペランドの上位バイト($40)が残っている
+    fn controller_port_bits(&self, bit: u8) -> u8 {
+        (self.open_bus & 0b1110_0000) | (bit & 1)
+    }
+
+    fn log_unmapped(&mut self, addr: u16, is_write: bool, data: u8) {
+        if let Some(log) = self.unmapped_log.as_mut() {
+            if log.len() == UNMAPPED_LOG_CAPACITY {
+                log.pop_front();
+            }
+            log.push_back(UnmappedAccess { addr, is_write, data });
+        }
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let data = self.mapper.borrow().read_prg(addr);
+        self.cheats.iter().fold(data, |data, cheat| cheat.patch(addr, data))
+    }
+
+    fn write_prg_rom(&mut self, addr: u16, data: u8) {
+        // NROMにはレジスタがないので、書き込むのはROMのバグ
+        if self.strict && self.mapper.borrow().id() == 0 {
+            panic!("Attempt to write to Cartridge ROM space {:x}", addr)
+        }
+        if let Some(hook) = self.mapper_write_hook.as_mut() {
+            hook(addr, data);
+        }
+        self.mapper.borrow_mut().write_prg(addr, data);
+    }
+
+    // デバッガ用の読み出し。レジスタは読むとフラグやアドレスが変わるので、オープンバスの値を返す
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            _ => self.open_bus,
         }
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
+    // mem_writeと違い、ROM領域ならPRG-ROMそのものを書き換える (プログラムの読み込み用)
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            self.mapper.borrow_mut().poke_prg(addr, data);
+        } else {
+            self.mem_write(addr, data);
         }
-        self.rom[addr as usize]
+    }
+
+    // IRQは複数の要因のORでつながっていて、要因が解除されるまでLowのまま
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.borrow().irq_pending() || self.apu.dmc.irq || self.apu.frame_counter.irq
     }
 
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.poll_nmi_interrupt()
+        let requested = std::mem::take(&mut self.nmi_requested);
+        self.ppu.poll_nmi_interrupt().or(if requested { Some(1) } else { None })
+    }
+
+    // vblankと関係なく、次の命令の前にNMIを起こす (テストやマッパー用)
+    pub fn request_nmi(&mut self) {
+        self.nmi_requested = true;
+    }
+
+    // Game Genieのコードを足す。CPUから見えるPRG-ROMの値だけが変わる
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        self.cheats.push(GameGenie::decode(code)?);
+        Ok(())
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    // NMIを使わずに画面を作るゲームでも描画できるように、vblankの開始ごとに呼ぶ
+    pub fn set_end_of_frame_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&NesPPU, &mut Joypad, &[f32]) + 'a,
+    {
+        self.end_of_frame_callback = Some(Box::new(callback));
+    }
+
+    // バンク切り替えのログを取ったり、マッパーに入る値を調べたりする
+    pub fn set_mapper_write_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(u16, u8) + 'a,
+    {
+        self.mapper_write_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_mapper_write_hook(&mut self) {
+        self.mapper_write_hook = None;
+    }
+
+    // 直前のvblankで取り出したサンプルを受け取る。コールバックを使わないフロントエンド用
+    pub fn take_frame_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.frame_samples)
+    }
+
+    // 前回呼ばれてからPPUが1フレーム描き終えたか
+    pub fn poll_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut NesPPU {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    // 読み書きせずに、addrがどこに繋がっているかだけを返す
+    pub fn describe_address(&self, addr: u16) -> MemRegion {
+        match addr {
+            RAM..=RAM_MIRRORS_END => MemRegion::Ram { offset: addr & 0x07ff },
+            0x2000..=PPU_REGISTERS_MIRRORS_END => MemRegion::PpuRegister(addr & 0x2007),
+            0x4014 => MemRegion::OamDma,
+            0x4000..=0x4013 | 0x4015 => MemRegion::Apu,
+            0x4016 => MemRegion::Controller(1),
+            0x4017 => MemRegion::Controller(2),
+            0x6000..=0x7fff => MemRegion::PrgRam { offset: addr - 0x6000 },
+            0x8000..=0xffff => MemRegion::PrgRom { offset: self.mapper.borrow().prg_rom_offset(addr) },
+            _ => MemRegion::Unmapped,
+        }
+    }
+
+    // フロントエンドはstep_frameの合間にここからボタンを設定できる
+    pub fn joypad1_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad1
+    }
+
+    pub fn joypad2_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad2
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.cpu_vram);
+        w.write_bytes(&self.prg_ram);
+        w.write_u64(self.cycles as u64);
+        self.joypad1.save_state(w);
+        self.joypad2.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        mapper::save_mapper_state(&*self.mapper.borrow(), w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_bytes(&mut self.cpu_vram)?;
+        r.read_bytes(&mut self.prg_ram)?;
+        self.cycles = r.read_u64()? as usize;
+        self.joypad1.load_state(r)?;
+        self.joypad2.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        mapper::load_mapper_state(&mut *self.mapper.borrow_mut(), r)
     }
 }
 
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let data = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize]
@@ -71,30 +415,36 @@ impl Mem for Bus<'_> {
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
             0x2007 => self.ppu.read_data(),
-            0x4000..=0x4015 => {
+            0x4000..=0x4014 => {
                 0
             },
+            0x4015 => self.apu.read_status(),
             0x4016 => {
-                self.joypad1.read()
+                let bit = self.joypad1.read();
+                self.controller_port_bits(bit)
             }
 
             0x4017 => {
-                // ignore joypad 2
-                0
-            },
+                let bit = self.joypad2.read();
+                self.controller_port_bits(bit)
+            }
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             },
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
             0x8000..=0xFFFF => self.read_prg_rom(addr),
             _ => {
-                println!("Ignoring mem access at {}", addr);
+                self.log_unmapped(addr, false, 0);
                 0
             }
-        }
+        };
+        self.open_bus = data;
+        data
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b11111111111;
@@ -102,22 +452,22 @@ impl Mem for Bus<'_> {
             },
             0x2000 => self.ppu.write_to_ctrl(data),
             0x2001 => self.ppu.write_to_mask(data),
-            0x2002 => panic!("attempt to write to PPU status register"),
+            0x2002 => {
+                if self.strict {
+                    panic!("attempt to write to PPU status register")
+                }
+            },
             0x2003 => self.ppu.write_to_oam_addr(data),
             0x2004 => self.ppu.write_to_oam_data(data),
             0x2005 => self.ppu.write_to_scroll(data),
             0x2006 => self.ppu.write_to_ppu_addr(data),
             0x2007 => self.ppu.write_to_data(data),
-            0x4000..=0x4013 | 0x4015 => {
-                //ignore APU 
-            }
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data),
 
+            // ストローブは両方のコントローラに同時に届く
             0x4016 => {
                 self.joypad1.write(data);
-            }
-
-            0x4017 => {
-                // ignore joypad 2
+                self.joypad2.write(data);
             }
 
             // https://wiki.nesdev.com/w/index.php/PPU_programmer_reference#OAM_DMA_.28.244014.29_.3E_write
@@ -130,32 +480,470 @@ impl Mem for Bus<'_> {
 
                 self.ppu.write_oam_dma(&buffer);
 
-                // todo: handle this eventually
-                // let add_cycles: u16 = if self.cycles % 2 == 1 { 514 } else { 513 };
-                // self.tick(add_cycles); //todo this will cause weird effects as PPU will have 513/514 * 3 ticks
+                // 転送の間CPUは止まる。奇数サイクルから始まると1サイクル余計に待つ
+                self.halt(if self.cycles & 1 == 1 { 514 } else { 513 });
             }
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_write(mirror_down_addr, data);
             },
-            0x8000..=0xFFFF => {
-                panic!("Attempt to write to Cartridge ROM space {:x}", addr)
-            },
-            _ => println!("Ignoring mem access at {}", addr)
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0x8000..=0xFFFF => self.write_prg_rom(addr, data),
+            _ => self.log_unmapped(addr, true, data),
 
         }
     }
 }
 
+impl SystemBus for Bus<'_> {
+    fn tick(&mut self, cycles: u8) {
+        Bus::tick(self, cycles)
+    }
+
+    fn cycles(&self) -> usize {
+        Bus::cycles(self)
+    }
+
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        Bus::poll_nmi_status(self)
+    }
+
+    fn irq_pending(&self) -> bool {
+        Bus::irq_pending(self)
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        Bus::poke(self, addr, data)
+    }
+
+    fn peek(&mut self, addr: u16) -> u8 {
+        Bus::peek(self, addr)
+    }
+
+    fn set_strict(&mut self, strict: bool) {
+        Bus::set_strict(self, strict)
+    }
+
+    fn take_halt_cycles(&mut self) -> usize {
+        std::mem::take(&mut self.halt_cycles)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::emu::cartridge::test;
+    use crate::emu::cartridge::{test, RomBuilder};
+    use crate::emu::cpu::CPU;
+    use crate::emu::mapper::test::TestMapper;
+    use crate::joypad::JoypadButton;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_mem_read_write_to_ram() {
-        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, &mut Joypad| {});
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, &mut Joypad| {}).unwrap();
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_describe_address() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.describe_address(0x0812), MemRegion::Ram { offset: 0x0012 });
+        assert_eq!(bus.describe_address(0x3ffe), MemRegion::PpuRegister(0x2006));
+        assert_eq!(bus.describe_address(0x4008), MemRegion::Apu);
+        assert_eq!(bus.describe_address(0x4014), MemRegion::OamDma);
+        assert_eq!(bus.describe_address(0x4016), MemRegion::Controller(1));
+        assert_eq!(bus.describe_address(0x4017), MemRegion::Controller(2));
+        assert_eq!(bus.describe_address(0x5000), MemRegion::Unmapped);
+        assert_eq!(bus.describe_address(0x6010), MemRegion::PrgRam { offset: 0x0010 });
+    }
+
+    #[test]
+    fn test_describe_address_follows_prg_bank() {
+        let mut bus = Bus::new(RomBuilder::new().mapper(4).prg(&[0; 0x10000]).build(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.describe_address(0x8004), MemRegion::PrgRom { offset: Some(0x0004) });
+        assert_eq!(bus.describe_address(0xfffc), MemRegion::PrgRom { offset: Some(0xfffc) });
+
+        // $8000に8KBバンク3を入れる
+        bus.mem_write(0x8000, 6);
+        bus.mem_write(0x8001, 3);
+        assert_eq!(bus.describe_address(0x8004), MemRegion::PrgRom { offset: Some(0x6004) });
+    }
+
+    #[test]
+    fn test_prg_ram_read_write() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x6000, 0x80);
+        bus.mem_write(0x7fff, 0x12);
+        assert_eq!(bus.mem_read(0x6000), 0x80);
+        assert_eq!(bus.mem_read(0x7fff), 0x12);
+    }
+
+    #[test]
+    fn test_oam_addr_and_data() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x2003, 0x20);
+        bus.mem_write(0x2004, 0x11);
+        bus.mem_write(0x2004, 0x22);
+        assert_eq!(bus.ppu().oam_data[0x20..0x22], [0x11, 0x22]);
+        assert_eq!(bus.ppu().oam_addr, 0x22);
+
+        // DMAも今のOAMADDRから書き始めて、末尾で折り返す
+        for i in 0..256u16 {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+        bus.mem_write(0x2003, 0x80);
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.ppu().oam_data[0x80], 0x00);
+        assert_eq!(bus.ppu().oam_data[0xff], 0x7f);
+        assert_eq!(bus.ppu().oam_data[0x00], 0x80);
+        assert_eq!(bus.ppu().oam_addr, 0x80);
+    }
+
+    #[test]
+    fn test_audio_callback_receives_samples() {
+        let mut frames = 0;
+        let mut total = 0;
+        {
+            let mut bus = Bus::with_audio_callback(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad, samples: &[f32]| {
+                frames += 1;
+                total += samples.len();
+            }).unwrap();
+            bus.mem_write(0x2000, 0x80);
+            // 1フレームは約29781 CPUサイクル
+            for _ in 0..29781 * 3 {
+                bus.tick(1);
+            }
+        }
+
+        assert_eq!(frames, 3);
+        // 44100Hz / 60fps = 735サンプル/フレーム。最初のvblankまでは少し短い
+        assert!((2 * 735..=3 * 735).contains(&total), "got {} samples", total);
+    }
+
+    #[test]
+    fn test_timing_callback() {
+        let mut timings = Vec::new();
+        {
+            let mut bus = Bus::with_timing_callback(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad, timing: PpuTiming| {
+                timings.push(timing);
+            }).unwrap();
+            bus.mem_write(0x2000, 0x80);
+            for _ in 0..29781 * 2 {
+                bus.tick(1);
+            }
+        }
+
+        assert_eq!(timings.len(), 2);
+        for (frame, timing) in timings.iter().enumerate() {
+            assert_eq!(timing.scanline, 241);
+            assert_eq!(timing.frame, frame as u64);
+            // CPU1サイクルはPPU3ドットなので、ドット1から少し進んでいることがある
+            assert!((1..=3).contains(&timing.cycle), "{:?}", timing);
+        }
+    }
+
+    #[test]
+    fn test_end_of_frame_callback_without_nmi() {
+        let mut nmi_frames = 0;
+        let mut frames = 0;
+        let mut total = 0;
+        {
+            let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| nmi_frames += 1).unwrap();
+            bus.set_end_of_frame_callback(|_ppu: &NesPPU, _joypad: &mut Joypad, samples: &[f32]| {
+                frames += 1;
+                total += samples.len();
+            });
+            bus.mem_write(0x2000, 0x00);
+            for _ in 0..29781 * 3 {
+                bus.tick(1);
+            }
+            // コールバックに渡したあとも、最後のフレームの分だけ残っている
+            let left = bus.take_frame_samples().len();
+            assert!((700..=740).contains(&left), "got {} samples", left);
+            assert!(bus.take_frame_samples().is_empty());
+        }
+
+        assert_eq!(nmi_frames, 0);
+        assert_eq!(frames, 3);
+        // NMIが無効でもサンプルはvblankごとに渡される
+        assert!((2 * 735..=3 * 735).contains(&total), "got {} samples", total);
+    }
+
+    #[test]
+    fn test_add_cheat() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.poke(0x91d9, 0xce);
+        bus.poke(0x91c5, 0x21);
+
+        assert_eq!(bus.add_cheat("SXIOPB"), Err("invalid Game Genie letter 'B' in SXIOPB".to_string()));
+        bus.add_cheat("SXIOPO").unwrap();
+        bus.add_cheat("AEKPIPZA").unwrap();
+        assert_eq!(bus.mem_read(0x91d9), 0xad);
+        // 比較値($02)と違うので差し替えない
+        assert_eq!(bus.mem_read(0x91c5), 0x21);
+        bus.poke(0x91c5, 0x02);
+        assert_eq!(bus.mem_read(0x91c5), 0x00);
+
+        bus.clear_cheats();
+        assert_eq!(bus.mem_read(0x91d9), 0xce);
+    }
+
+    #[test]
+    fn test_joypad_accessors() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.bus_mut().joypad1_mut().set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        cpu.bus_mut().joypad2_mut().set_button_pressed_status(JoypadButton::BUTTON_B, true);
+
+        cpu.load_and_run(vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, // LDA #$01 / STA $4016
+            0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #$00 / STA $4016
+            0xad, 0x16, 0x40, 0x85, 0x10, // LDA $4016 / STA $10 (1P A)
+            0xad, 0x17, 0x40, 0x85, 0x11, // LDA $4017 / STA $11 (2P A)
+            0xad, 0x17, 0x40, 0x85, 0x12, // LDA $4017 / STA $12 (2P B)
+            0x00,
+        ]);
+
+        // 上位ビットはオペランドの上位バイト$40のオープンバス
+        assert_eq!(cpu.mem_read(0x10), 0x41);
+        assert_eq!(cpu.mem_read(0x11), 0x40);
+        assert_eq!(cpu.mem_read(0x12), 0x41);
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x0012, 0x34);
+        assert_eq!(bus.peek(0x0812), 0x34);
+
+        // $2002を読むとvblankのフラグが消えるが、peekでは消えない
+        bus.ppu.status.set_vblank_status(true);
+        bus.peek(0x2002);
+        assert!(bus.ppu.status.is_in_vblank());
+        bus.mem_read(0x2002);
+        assert!(!bus.ppu.status.is_in_vblank());
+    }
+
+    #[test]
+    fn test_controller_reads_keep_open_bus_bits() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.joypad1_mut().set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        bus.mem_write(0x0000, 0xff);
+        // bit1-4は出力されないので0のまま
+        assert_eq!(bus.mem_read(0x4016), 0xe1);
+        // 直前の読み出し値(0xe1)が残っている
+        assert_eq!(bus.mem_read(0x4016), 0xe0);
+        assert_eq!(bus.mem_read(0x0010), 0x00);
+        assert_eq!(bus.mem_read(0x4017), 0x00);
+        bus.mem_read(0x0000);
+        assert_eq!(bus.mem_read(0x4016), 0xe0);
+    }
+
+    #[test]
+    fn test_stack_contents_keeps_open_bus() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.bus_mut().mem_write(0x01fe, 0x12);
+        cpu.bus_mut().mem_write(0x01ff, 0x34);
+        cpu.stack_pointer = 0xfd;
+        cpu.bus_mut().open_bus = 0xa0;
+
+        // デバッガでスタックを見ても、次の$4016の上位ビットは変わらない
+        assert_eq!(cpu.stack_contents(), vec![0x12, 0x34]);
+        assert_eq!(cpu.bus().open_bus, 0xa0);
+    }
+
+    #[test]
+    fn test_joypad_reads_one_after_eight_bits() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.joypad1_mut().set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        let reads: Vec<u8> = (0..10).map(|_| bus.mem_read(0x4016)).collect();
+        assert_eq!(reads, vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 1]);
+
+        // 次のストローブでまた先頭から
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016), 1);
+        assert_eq!(bus.mem_read(0x4016), 0);
+    }
+
+    #[test]
+    fn test_unmapped_access_log() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x5000, 0x12);
+        assert!(bus.unmapped_accesses().is_empty());
+
+        bus.set_unmapped_access_log_enabled(true);
+        bus.mem_write(0x5000, 0x34);
+        assert_eq!(bus.mem_read(0x4020), 0);
+        bus.mem_write(0x0000, 0x56);
+        assert_eq!(
+            bus.unmapped_accesses(),
+            vec![
+                UnmappedAccess { addr: 0x5000, is_write: true, data: 0x34 },
+                UnmappedAccess { addr: 0x4020, is_write: false, data: 0 },
+            ]
+        );
+
+        for i in 0..UNMAPPED_LOG_CAPACITY as u16 + 10 {
+            bus.mem_read(0x5000 + i);
+        }
+        let log = bus.unmapped_accesses();
+        assert_eq!(log.len(), UNMAPPED_LOG_CAPACITY);
+        assert_eq!(log[0].addr, 0x5000 + 10);
+    }
+
+    #[test]
+    fn test_power_on_zeroed_by_default() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.mem_read(0x0000), 0);
+        assert_eq!(bus.mem_read(0x07ff), 0);
+    }
+
+    #[test]
+    fn test_power_on_filled() {
+        let mut bus = Bus::with_power_on_state(test::test_rom(), PowerOnState::Filled(0xff), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        for addr in (0x0000..0x0800).step_by(0x7f) {
+            assert_eq!(bus.mem_read(addr), 0xff);
+        }
+        // mirror
+        assert_eq!(bus.mem_read(0x1800), 0xff);
+    }
+
+    #[test]
+    fn test_power_on_pattern() {
+        let mut bus = Bus::with_power_on_state(test::test_rom(), PowerOnState::Pattern, |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let first: Vec<u8> = (0..8).map(|addr| bus.mem_read(addr)).collect();
+        assert_eq!(first, vec![0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(bus.mem_read(0x07ff), 0xff);
+    }
+
+    #[test]
+    fn test_dmc_dma_stalls_cpu() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0b0001_0000);
+        assert_eq!(bus.mem_read(0x4015) & 0b0001_0000, 0b0001_0000);
+
+        let before = bus.cycles();
+        bus.tick(2);
+        assert_eq!(bus.cycles(), before + 2 + 4);
+
+        // 1バイトのサンプルは読み終わったので、もうDMAは起きない
+        assert_eq!(bus.mem_read(0x4015) & 0b0001_0000, 0);
+        bus.tick(2);
+        assert_eq!(bus.cycles(), before + 2 + 4 + 2);
+    }
+
+    #[test]
+    fn test_prg_rom_16kb_is_mirrored() {
+        let mut prg = vec![0; 0x4000];
+        prg[0x0000] = 0x11;
+        prg[0x3fff] = 0x22;
+        let mut bus = Bus::new(RomBuilder::new().prg(&prg).build(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+
+        assert_eq!(bus.mem_read(0x8000), 0x11);
+        assert_eq!(bus.mem_read(0xc000), 0x11);
+        assert_eq!(bus.mem_read(0xbfff), 0x22);
+        assert_eq!(bus.mem_read(0xffff), 0x22);
+    }
+
+    #[test]
+    fn test_prg_rom_32kb_is_not_mirrored() {
+        let mut prg = vec![0; 0x8000];
+        prg[0x0000] = 0x11;
+        prg[0x4000] = 0x33;
+        let mut bus = Bus::new(RomBuilder::new().prg(&prg).build(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+
+        assert_eq!(bus.mem_read(0x8000), 0x11);
+        assert_eq!(bus.mem_read(0xc000), 0x33);
+    }
+
+    #[test]
+    fn test_prg_rom_odd_size_does_not_panic() {
+        let mut bus = Bus::new(RomBuilder::new().prg(&[0xea; 0x2000]).build(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.mem_read(0x9fff), 0xea);
+        // 8KBは2のべき乗なのでそのままミラーされる
+        assert_eq!(bus.mem_read(0xa000), 0xea);
+
+        let mut bus = Bus::new(RomBuilder::new().prg(&[0xea; 0x6000]).build(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.mem_read(0xdfff), 0xea);
+        assert_eq!(bus.mem_read(0xe000), 0);
+    }
+
+    #[test]
+    fn test_permissive_writes_are_ignored() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let status = bus.ppu().status.snapshot();
+        bus.mem_write(0x2002, 0xff);
+        bus.mem_write(0x200a, 0xff);
+        assert_eq!(bus.ppu().status.snapshot(), status);
+
+        bus.mem_write(0x8000, 0x55);
+        assert_eq!(bus.mem_read(0x8000), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to write to PPU status register")]
+    fn test_strict_write_to_ppu_status_panics() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.set_strict(true);
+        bus.mem_write(0x2002, 0xff);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attempt to write to Cartridge ROM space 8000")]
+    fn test_strict_write_to_rom_panics() {
+        let mut bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        bus.set_strict(true);
+        bus.mem_write(0x8000, 0x55);
+    }
+
+    #[test]
+    fn test_mapper_write_hook() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let mapper = Rc::new(RefCell::new(TestMapper::new()));
+        let mut bus = Bus::with_mapper(mapper, PowerOnState::default(), |_ppu: &NesPPU, _joypad: &mut Joypad| {});
+        let log = writes.clone();
+        bus.set_mapper_write_hook(move |addr, data| log.borrow_mut().push((addr, data)));
+
+        // RAMやPRG-RAMへの書き込みでは呼ばれない
+        bus.mem_write(0x0010, 0x01);
+        bus.mem_write(0x6000, 0x02);
+        bus.mem_write(0xc123, 0x03);
+        assert_eq!(bus.mem_read(0x8000), 3);
+        assert_eq!(*writes.borrow(), vec![(0xc123, 0x03)]);
+
+        bus.clear_mapper_write_hook();
+        bus.mem_write(0x8000, 0x05);
+        assert_eq!(bus.mem_read(0x8000), 5);
+        assert_eq!(writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_unsupported_mapper_is_an_error() {
+        let rom = RomBuilder::new().mapper(3).build();
+        let result = Bus::new(rom, |_ppu: &NesPPU, _joypad: &mut Joypad| {});
+        assert_eq!(result.err().map(|e| e.to_string()), Some("mapper 3 is not supported".to_string()));
+    }
+
+    #[test]
+    fn test_reset_vector_from_built_rom() {
+        let mut prg = vec![0xea; 0x8000];
+        prg[0x7ffc] = 0x34;
+        prg[0x7ffd] = 0x82;
+        let rom = RomBuilder::new().prg(&prg).build();
+
+        let mut bus = Bus::new(rom, |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.mem_read_u16(0xfffc), 0x8234);
+        assert_eq!(bus.mem_read(0x8000), 0xea);
+    }
 }
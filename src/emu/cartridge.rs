@@ -1,3 +1,14 @@
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+use memmap2::{MmapMut, MmapOptions};
+use std::fmt;
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+use std::fs::File;
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+use std::io::{ErrorKind, Read};
+use std::ops::{Deref, DerefMut};
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+use std::path::Path;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
@@ -9,26 +20,157 @@ pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
+    // 4つとも同じネームテーブル。Aは$2000、Bは$2400の実体を使う (AxROMなど)
+    SINGLE_SCREEN_A,
+    SINGLE_SCREEN_B,
+}
+
+// PRG/CHRの中身。ファイルをメモリマップしたものはコピーオンライトなので書き換えてもファイルには残らない
+pub enum RomData {
+    Owned(Vec<u8>),
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    Mapped(MmapMut),
+}
+
+impl Deref for RomData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            RomData::Owned(data) => data,
+            #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+            RomData::Mapped(map) => map,
+        }
+    }
+}
+
+impl DerefMut for RomData {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            RomData::Owned(data) => data,
+            #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+            RomData::Mapped(map) => map,
+        }
+    }
+}
+
+impl From<Vec<u8>> for RomData {
+    fn from(data: Vec<u8>) -> Self {
+        RomData::Owned(data)
+    }
+}
+
+impl PartialEq<Vec<u8>> for RomData {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for RomData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomData::Owned(data) => write!(f, "Owned({} bytes)", data.len()),
+            #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+            RomData::Mapped(map) => write!(f, "Mapped({} bytes)", map.len()),
+        }
+    }
 }
 
 pub struct Rom {
-    pub prg_rom: Vec<u8>,
-    pub chr_rom: Vec<u8>,
+    pub prg_rom: RomData,
+    pub chr_rom: RomData,
     pub mapper: u8,
-    pub screen_mirroring: Mirroring
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
 }
 
-impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+#[derive(Debug, PartialEq, Clone)]
+pub struct RomInfo {
+    pub mapper: u8,
+    pub prg_rom_kb: usize,
+    pub chr_rom_kb: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub chr_is_ram: bool,
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mapper {}, PRG {}KB, CHR {}, mirroring {:?}, battery {}",
+            self.mapper,
+            self.prg_rom_kb,
+            if self.chr_is_ram { "RAM".to_string() } else { format!("{}KB", self.chr_rom_kb) },
+            self.mirroring,
+            if self.battery { "yes" } else { "no" },
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RomError {
+    NotINes,
+    // 先頭が"NES\x1A"ではない。foundは実際に読んだ4バイト
+    InvalidMagic { found: [u8; 4] },
+    Nes2NotSupported,
+    UnsupportedMapper(u8),
+    // ヘッダに書かれた大きさよりデータが短い
+    Truncated { expected: usize, actual: usize },
+    Io(String),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::NotINes => write!(f, "File is not in iNES file format"),
+            RomError::InvalidMagic { found } => {
+                write!(
+                    f,
+                    "File is not an iNES ROM: expected 4E 45 53 1A (\"NES\\x1A\") at the start, found {:02X} {:02X} {:02X} {:02X}",
+                    found[0], found[1], found[2], found[3]
+                )?;
+                if found[0..2] == *b"PK" {
+                    write!(f, " (this looks like a zip archive; extract the .nes file first)")?;
+                }
+                Ok(())
+            }
+            RomError::Nes2NotSupported => write!(f, "NES2.0 format is not supported"),
+            RomError::UnsupportedMapper(id) => write!(f, "mapper {} is not supported", id),
+            RomError::Truncated { expected, actual } => {
+                write!(f, "ROM is {} bytes, but the header needs {} bytes", actual, expected)
+            }
+            RomError::Io(message) => write!(f, "failed to read ROM: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+// iNESヘッダを読んだ結果。PRG/CHRはファイル先頭からの位置
+struct Header {
+    prg_rom: std::ops::Range<usize>,
+    chr_rom: std::ops::Range<usize>,
+    mapper: u8,
+    screen_mirroring: Mirroring,
+    battery: bool,
+}
+
+impl Header {
+    // rawは少なくともヘッダの16バイト。file_lenはファイル全体の長さ
+    fn parse(raw: &[u8], file_len: usize) -> Result<Header, RomError> {
+        if raw.len() >= 4 && raw[0..4] != NES_TAG {
+            return Err(RomError::InvalidMagic { found: [raw[0], raw[1], raw[2], raw[3]] });
+        }
+        if raw.len() < 16 {
+            return Err(RomError::NotINes);
         }
 
         let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
 
         let ines_ver = (raw[7] >> 2) & 0b11;
         if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+            return Err(RomError::Nes2NotSupported);
         }
 
         let four_screen = raw[6] & 0b1000 != 0;
@@ -43,18 +185,164 @@ impl Rom {
         let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
 
         let skip_trainer = raw[6] & 0b100 != 0;
+        let battery = raw[6] & 0b10 != 0;
 
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        if file_len < chr_rom_start + chr_rom_size {
+            return Err(RomError::Truncated { expected: chr_rom_start + chr_rom_size, actual: file_len });
+        }
 
-        Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+        Ok(Header {
+            prg_rom: prg_rom_start..(prg_rom_start + prg_rom_size),
+            chr_rom: chr_rom_start..(chr_rom_start + chr_rom_size),
             mapper,
             screen_mirroring,
+            battery,
         })
     }
+
+    fn into_rom(self, prg_rom: RomData, chr_rom: RomData) -> Rom {
+        Rom {
+            prg_rom,
+            chr_rom,
+            mapper: self.mapper,
+            screen_mirroring: self.screen_mirroring,
+            battery: self.battery,
+        }
+    }
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        Rom::from_bytes(raw).map_err(|e| e.to_string())
+    }
+
+    // ファイルシステムを使わないので、WASMなどでも読み込める
+    pub fn from_bytes(raw: &[u8]) -> Result<Rom, RomError> {
+        let header = Header::parse(raw, raw.len())?;
+        let prg_rom = raw[header.prg_rom.clone()].to_vec().into();
+        let chr_rom = raw[header.chr_rom.clone()].to_vec().into();
+        Ok(header.into_rom(prg_rom, chr_rom))
+    }
+
+    // ファイル全体を読み込まずにPRG/CHRをメモリマップする。大きなROMでも起動時のメモリが増えない
+    // マップできなければ普通に読み込む
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    pub fn open_mapped(path: &Path) -> Result<Rom, RomError> {
+        let io_error = |e: std::io::Error| RomError::Io(e.to_string());
+        let file = File::open(path).map_err(io_error)?;
+        let len = file.metadata().map_err(io_error)?.len() as usize;
+
+        // ヘッダより短いファイルでもfrom_bytesと同じエラーになるよう、あるだけ読む
+        let mut raw_header = [0; 16];
+        let header_len = len.min(raw_header.len());
+        (&file).read_exact(&mut raw_header[..header_len]).map_err(|e| match e.kind() {
+            // 読んでいる間にファイルが短くなった
+            ErrorKind::UnexpectedEof => RomError::NotINes,
+            _ => io_error(e),
+        })?;
+        let header = Header::parse(&raw_header[..header_len], len)?;
+
+        let map = |range: std::ops::Range<usize>| -> std::io::Result<RomData> {
+            if range.is_empty() {
+                return Ok(RomData::Owned(Vec::new()));
+            }
+            // SAFETY: map_copyはプライベートなコピーオンライトのマップなので、こちらの書き換えはファイルに届かない
+            // ただしマップしている間に他からファイルを切り詰められるとアクセスでSIGBUSになり、
+            // まだ書き換えていないページは他からの変更が見えることがある。
+            // ROMファイルはRomを使っている間は切り詰めも変更もされないものとする
+            let map = unsafe { MmapOptions::new().offset(range.start as u64).len(range.len()).map_copy(&file)? };
+            Ok(RomData::Mapped(map))
+        };
+        match (map(header.prg_rom.clone()), map(header.chr_rom.clone())) {
+            (Ok(prg_rom), Ok(chr_rom)) => Ok(header.into_rom(prg_rom, chr_rom)),
+            _ => Rom::from_bytes(&std::fs::read(path).map_err(io_error)?),
+        }
+    }
+
+    pub fn info(&self) -> RomInfo {
+        RomInfo {
+            mapper: self.mapper,
+            prg_rom_kb: self.prg_rom.len() / 1024,
+            chr_rom_kb: self.chr_rom.len() / 1024,
+            mirroring: self.screen_mirroring.clone(),
+            battery: self.battery,
+            chr_is_ram: self.chr_rom.is_empty(),
+        }
+    }
+
+    // CHR-ROMをbank_sizeバイトずつに分ける (タイル抽出ツール用)
+    // 大きさが割り切れなければ最後のバンクは短くなる。CHR-RAMかbank_sizeが0なら空
+    pub fn chr_banks(&self, bank_size: usize) -> Vec<&[u8]> {
+        if bank_size == 0 {
+            return Vec::new();
+        }
+        self.chr_rom.chunks(bank_size).collect()
+    }
+}
+
+pub struct RomBuilder {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    mapper: u8,
+    mirroring: Mirroring,
+    battery: bool,
+}
+
+impl RomBuilder {
+    pub fn new() -> Self {
+        RomBuilder {
+            prg_rom: vec![0; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![0; CHR_ROM_PAGE_SIZE],
+            mapper: 0,
+            mirroring: Mirroring::HORIZONTAL,
+            battery: false,
+        }
+    }
+
+    pub fn prg(mut self, prg_rom: &[u8]) -> Self {
+        self.prg_rom = prg_rom.to_vec();
+        self
+    }
+
+    pub fn chr(mut self, chr_rom: &[u8]) -> Self {
+        self.chr_rom = chr_rom.to_vec();
+        self
+    }
+
+    pub fn mapper(mut self, mapper: u8) -> Self {
+        self.mapper = mapper;
+        self
+    }
+
+    pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+        self.mirroring = mirroring;
+        self
+    }
+
+    pub fn battery(mut self, battery: bool) -> Self {
+        self.battery = battery;
+        self
+    }
+
+    pub fn build(self) -> Rom {
+        Rom {
+            prg_rom: self.prg_rom.into(),
+            chr_rom: self.chr_rom.into(),
+            mapper: self.mapper,
+            screen_mirroring: self.mirroring,
+            battery: self.battery,
+        }
+    }
+}
+
+impl Default for RomBuilder {
+    fn default() -> Self {
+        RomBuilder::new()
+    }
 }
+
 pub mod test {
 
     use super::*;
@@ -87,7 +375,7 @@ pub mod test {
     pub fn test_rom() -> Rom {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -116,6 +404,22 @@ pub mod test {
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
     }
 
+    #[test]
+    fn test_chr_banks() {
+        let chr: Vec<u8> = (0..CHR_ROM_PAGE_SIZE).map(|i| (i / 1024) as u8 ^ i as u8).collect();
+        let rom = RomBuilder::new().chr(&chr).build();
+
+        let banks = rom.chr_banks(1024);
+        assert_eq!(banks.len(), 8);
+        assert!(banks.iter().all(|bank| bank.len() == 1024));
+        assert_eq!(banks.concat(), chr);
+        // chunks(0)はパニックするので、0は空として扱う
+        assert!(rom.chr_banks(0).is_empty());
+
+        let rom = RomBuilder::new().chr(&[]).build();
+        assert!(rom.chr_banks(1024).is_empty());
+    }
+
     #[test]
     fn test_with_trainer() {
         let test_rom = create_rom(TestRom {
@@ -150,6 +454,189 @@ pub mod test {
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
     }
 
+    #[test]
+    fn test_nrom_accepts_odd_prg_size() {
+        let prg: Vec<u8> = (0..3 * PRG_ROM_PAGE_SIZE).map(|i| (i / PRG_ROM_PAGE_SIZE) as u8).collect();
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x03, 0x01, 0x00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: prg,
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        // 16KB/32KB以外のNROMも読み込めて、$8000-$FFFFには先頭の32KBが見える
+        let rom = Rom::from_bytes(&test_rom).unwrap();
+        assert_eq!(rom.info().prg_rom_kb, 48);
+        let mapper = crate::emu::mapper::from_rom(rom).unwrap();
+        assert_eq!(mapper.borrow().read_prg(0x8000), 0);
+        assert_eq!(mapper.borrow().read_prg(0xffff), 1);
+    }
+
+    #[test]
+    fn test_info() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31 | 0b10, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let info = Rom::new(&test_rom).unwrap().info();
+
+        assert_eq!(info.mapper, 3);
+        assert_eq!(info.prg_rom_kb, 32);
+        assert_eq!(info.chr_rom_kb, 8);
+        assert_eq!(info.mirroring, Mirroring::VERTICAL);
+        assert!(info.battery);
+        assert!(!info.chr_is_ram);
+        assert_eq!(
+            info.to_string(),
+            "mapper 3, PRG 32KB, CHR 8KB, mirroring VERTICAL, battery yes"
+        );
+    }
+
+    #[test]
+    fn test_info_chr_ram() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let info = Rom::new(&test_rom).unwrap().info();
+
+        assert_eq!(info.mapper, 0);
+        assert_eq!(info.prg_rom_kb, 16);
+        assert_eq!(info.chr_rom_kb, 0);
+        assert_eq!(info.mirroring, Mirroring::HORIZONTAL);
+        assert!(!info.battery);
+        assert!(info.chr_is_ram);
+        assert_eq!(
+            info.to_string(),
+            "mapper 0, PRG 16KB, CHR RAM, mirroring HORIZONTAL, battery no"
+        );
+    }
+
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_open_mapped() {
+        let mut prg = vec![0xea; 2 * PRG_ROM_PAGE_SIZE];
+        prg[0x7ffc] = 0x34;
+        prg[0x7ffd] = 0x92;
+        // トレーナーがあるとPRGの位置がページ境界からずれる
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x04, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: Some(vec![0; 512]),
+            pgp_rom: prg,
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let path = std::env::temp_dir().join(format!("nes_emu_mapped_{}.nes", std::process::id()));
+        std::fs::write(&path, &test_rom).unwrap();
+
+        let rom = Rom::open_mapped(&path).unwrap();
+        assert!(matches!(rom.prg_rom, RomData::Mapped(_)));
+        assert_eq!(rom.chr_rom, vec![2; CHR_ROM_PAGE_SIZE]);
+
+        let mapper = crate::emu::mapper::from_rom(rom).unwrap();
+        let reset = u16::from_le_bytes([mapper.borrow().read_prg(0xfffc), mapper.borrow().read_prg(0xfffd)]);
+        assert_eq!(reset, 0x9234);
+
+        // 書き換えてもファイルはそのまま
+        mapper.borrow_mut().poke_prg(0xfffc, 0x00);
+        assert_eq!(mapper.borrow().read_prg(0xfffc), 0x00);
+        assert_eq!(std::fs::read(&path).unwrap(), test_rom);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_open_mapped_missing_file() {
+        let result = Rom::open_mapped(Path::new("/nonexistent/rom.nes"));
+        assert!(matches!(result, Err(RomError::Io(_))));
+    }
+
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_open_mapped_short_file() {
+        let path = std::env::temp_dir().join(format!("nes_emu_short_{}.nes", std::process::id()));
+        std::fs::write(&path, [0x4E, 0x45, 0x53, 0x1A, 0x02]).unwrap();
+
+        // ヘッダの途中で終わっていてもIoではなくfrom_bytesと同じエラー
+        let result = Rom::open_mapped(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.err(), Some(RomError::NotINes));
+    }
+
+    #[test]
+    fn test_builder() {
+        let rom = RomBuilder::new()
+            .prg(&[1; 2 * PRG_ROM_PAGE_SIZE])
+            .chr(&[2; CHR_ROM_PAGE_SIZE])
+            .mapper(3)
+            .mirroring(Mirroring::VERTICAL)
+            .build();
+
+        assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert!(!rom.battery);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        const RAW: [u8; 16 + 0x4000 + 0x2000] = {
+            let mut raw = [0; 16 + 0x4000 + 0x2000];
+            raw[0] = 0x4E;
+            raw[1] = 0x45;
+            raw[2] = 0x53;
+            raw[3] = 0x1A;
+            raw[4] = 1;
+            raw[5] = 1;
+            raw[6] = 0b1;
+            raw[16] = 0xa9;
+            raw[16 + 0x4000] = 0x3c;
+            raw
+        };
+
+        let rom = Rom::from_bytes(&RAW).unwrap();
+        assert_eq!(rom.prg_rom.len(), 0x4000);
+        assert_eq!(rom.prg_rom[0], 0xa9);
+        assert_eq!(rom.chr_rom[0], 0x3c);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+
+        assert_eq!(Rom::from_bytes(&RAW[..4]).err(), Some(RomError::NotINes));
+        assert_eq!(
+            Rom::from_bytes(&RAW[..0x100]).err(),
+            Some(RomError::Truncated { expected: RAW.len(), actual: 0x100 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_magic() {
+        let zip = [0x50, 0x4B, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let err = Rom::from_bytes(&zip).err().unwrap();
+        assert_eq!(err, RomError::InvalidMagic { found: [0x50, 0x4B, 0x03, 0x04] });
+        assert!(err.to_string().contains("found 50 4B 03 04"));
+        assert!(err.to_string().contains("zip"));
+
+        let err = Rom::from_bytes(b"hello, world").err().unwrap();
+        assert_eq!(err, RomError::InvalidMagic { found: *b"hell" });
+        assert!(!err.to_string().contains("zip"));
+
+        // 4バイトに満たなければ中身は分からない
+        assert_eq!(Rom::from_bytes(b"NE").err(), Some(RomError::NotINes));
+    }
+
     #[test]
     fn test_nes2_is_not_supported() {
         let test_rom = create_rom(TestRom {
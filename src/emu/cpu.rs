@@ -1,17 +1,64 @@
 use std::collections::HashMap;
-use crate::emu::opcodes;
+use crate::emu::opcodes::{self, InstructionKind};
 use crate::emu::bus::Bus;
 use crate::emu::interrupt::*;
+use crate::emu::profiler::{ProfileEntry, Profiler};
+use crate::emu::trace::{TraceHook, TraceInfo};
+use crate::savestate::{StateReader, StateWriter};
 
+// ステータスレジスタのうち、スタックに積んだときだけ意味を持つビット
+const BREAK_FLAG: u8 = 0b0001_0000;
+const UNUSED_FLAG: u8 = 0b0010_0000;
+// 1の間はIRQを受け付けない
+const INTERRUPT_DISABLE_FLAG: u8 = 0b0000_0100;
 
-pub struct CPU<'a> {
+pub struct CPU<'a, B = Bus<'a>> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
     pub status: u8,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    bus: Bus<'a>
+    bus: B,
+    strict: bool,
+    // 命令表。テストでは抜けのある表に差し替えて、未知の命令の扱いを確かめる
+    opcode_table: &'static HashMap<u8, &'static opcodes::OpCode>,
+    // trueならKIL($02など)でCPUが止まる。falseなら何もしない命令として読み飛ばす
+    jam_on_kil: bool,
+    trace_hook: Option<TraceHook<'a>>,
+    // 有効にしたときだけ確保する
+    opcode_counts: Option<Box<[u64; 256]>>,
+    instruction_budget: Option<u64>,
+    profiler: Option<Profiler>,
+    // ページをまたぐ読み出しで、上位バイトを直す前のアドレスも読む
+    dummy_reads: bool,
+    // trueのオペコードを実行する直前でrun_with_callbackが止まる。設定したときだけ確保する
+    opcode_breakpoints: Option<Box<[bool; 256]>>,
+    // ブレークポイントで止まったアドレス。再開したときにそこだけは止まらずに実行する
+    resume_from_break: Option<u16>,
+}
+
+// レジスタ一式。デバッガやテストで丸ごと保存・復元するため
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CpuState {
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub program_counter: u16,
+    pub stack_pointer: u8,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RunStop {
+    Break,
+    UnknownOpcode { opcode: u8, pc: u16 },
+    // set_instruction_budgetで決めた数の命令を実行した
+    BudgetExhausted,
+    // jam_on_kilのときにKILを実行した。pcはKILの位置で、リセットするまで先に進まない
+    Jammed { opcode: u8, pc: u16 },
+    // break_on_opcodeなどで指定した命令に来た。pcの命令はまだ実行していない
+    Breakpoint { opcode: u8, pc: u16 },
 }
 
 #[derive(Debug)]
@@ -48,7 +95,35 @@ pub trait Mem {
     }
 }
 
-impl Mem for CPU<'_> {
+// CPUから見たバス。NESのBusのほかに、テスト用にRAMだけのバスなどもつなげる
+pub trait SystemBus: Mem {
+    fn tick(&mut self, cycles: u8);
+    fn cycles(&self) -> usize;
+    fn poll_nmi_status(&mut self) -> Option<u8>;
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // ROM領域も書き換える書き込み。RAMしかないならmem_writeと同じ
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.mem_write(addr, data)
+    }
+
+    // デバッガ用の副作用のない読み出し。RAMしかないならmem_readと同じ
+    fn peek(&mut self, addr: u16) -> u8 {
+        self.mem_read(addr)
+    }
+
+    fn set_strict(&mut self, _strict: bool) {}
+
+    // RDY線。DMAなどでCPUを止めるサイクル数を返して0に戻す
+    fn take_halt_cycles(&mut self) -> usize {
+        0
+    }
+}
+
+impl<B: SystemBus> Mem for CPU<'_, B> {
     fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
@@ -68,8 +143,8 @@ impl Mem for CPU<'_> {
 
 }
 
-impl<'a> CPU<'a> {
-    pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
+impl<'a, B: SystemBus> CPU<'a, B> {
+    pub fn new(bus: B) -> Self {
         CPU {
             register_a: 0,
             register_x: 0,
@@ -77,8 +152,116 @@ impl<'a> CPU<'a> {
             status: 0b0010_0100,
             program_counter: 0,
             stack_pointer: 0xfd,
-            bus
+            bus,
+            strict: false,
+            opcode_table: &opcodes::OPECODES_MAP,
+            jam_on_kil: false,
+            trace_hook: None,
+            opcode_counts: None,
+            instruction_budget: None,
+            profiler: None,
+            dummy_reads: true,
+            opcode_breakpoints: None,
+            resume_from_break: None,
+        }
+    }
+
+    // run_with_callback 1回で実行する命令数の上限。Noneなら止まるまで回り続ける
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_budget = budget;
+    }
+
+    pub fn break_on_opcode(&mut self, opcode: u8) {
+        self.opcode_breakpoints.get_or_insert_with(|| Box::new([false; 256]))[opcode as usize] = true;
+    }
+
+    pub fn break_on_instruction_kind(&mut self, kind: InstructionKind) {
+        for op in opcodes::CPU_OPS_CODES.iter().filter(|op| kind.includes(op)) {
+            self.break_on_opcode(op.code);
+        }
+    }
+
+    pub fn clear_opcode_breakpoints(&mut self) {
+        self.opcode_breakpoints = None;
+    }
+
+    // PCをrange_sizeバイトごとに区切って、使ったサイクル数を数える。Noneで止める
+    pub fn set_profiler_range_size(&mut self, range_size: Option<usize>) {
+        self.profiler = range_size.map(Profiler::new);
+    }
+
+    // 無効のときは空
+    pub fn profile_report(&self) -> Vec<ProfileEntry> {
+        self.profiler.as_ref().map(Profiler::report).unwrap_or_default()
+    }
+
+    pub fn set_opcode_histogram_enabled(&mut self, enabled: bool) {
+        self.opcode_counts = if enabled { Some(Box::new([0; 256])) } else { None };
+    }
+
+    // 無効のときはすべて0
+    pub fn opcode_histogram(&self) -> [u64; 256] {
+        self.opcode_counts.as_deref().copied().unwrap_or([0; 256])
+    }
+
+    pub fn set_trace_hook(&mut self, hook: TraceHook<'a>) {
+        self.trace_hook = Some(hook);
+    }
+
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
+    }
+
+    fn call_trace_hook(&mut self) {
+        let mut hook = match self.trace_hook.take() {
+            Some(hook) => hook,
+            None => return,
+        };
+
+        let pc = self.program_counter;
+        let code = self.mem_read(pc);
+        let len = opcodes::OPECODES_MAP.get(&code).map_or(1, |op| op.len);
+        let mut opcode = [code, 0, 0];
+        for i in 1..len {
+            opcode[i as usize] = self.mem_read(pc.wrapping_add(i as u16));
         }
+
+        hook(&TraceInfo {
+            pc,
+            opcode,
+            len,
+            register_a: self.register_a,
+            register_x: self.register_x,
+            register_y: self.register_y,
+            status: self.status,
+            stack_pointer: self.stack_pointer,
+            cycles: self.bus.cycles(),
+        });
+        self.trace_hook = Some(hook);
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.bus.cycles()
+    }
+
+    // 暴走したコードを見つけるため、KILを実機どおりCPUの停止として扱う
+    pub fn set_jam_on_kil(&mut self, jam: bool) {
+        self.jam_on_kil = jam;
+    }
+
+    // strictだと未知の命令で実行を止める。そうでなければNOPとして扱う
+    // バスもstrictにして、書き込めない場所への書き込みでpanicさせる
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+        self.bus.set_strict(strict);
     }
 
     fn page_cross(addr1: u16, addr2: u16) -> bool {
@@ -137,6 +320,21 @@ impl<'a> CPU<'a> {
         }
     }
 
+    // 実機ではページをまたぐと、上位バイトを繰り上げる前のアドレスを一度読んでしまう
+    // PPUレジスタなど読むと状態が変わる所では2回読んだことになる
+    pub fn set_dummy_reads(&mut self, enabled: bool) {
+        self.dummy_reads = enabled;
+    }
+
+    // 値を読む命令のオペランド。ページをまたげば空読みもする
+    fn read_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        if page_cross && self.dummy_reads {
+            self.mem_read(addr.wrapping_sub(0x100));
+        }
+        (addr, page_cross)
+    }
+
     fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
         match mode {
             AddressingMode::Immediate => (self.program_counter, false),
@@ -144,13 +342,27 @@ impl<'a> CPU<'a> {
         }
     }
 
+    // TAS/AHX/SHX/SHYの書き込み。書く値はvalueとベースアドレスの上位バイト+1のAND
+    // ページをまたぐと繰り上げが間に合わず、アドレスの上位バイトが書く値に化ける
+    fn unstable_store(&mut self, mode: &AddressingMode, value: u8) {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        let index = match mode {
+            AddressingMode::Absolute_X => self.register_x,
+            _ => self.register_y,
+        };
+        let base = addr.wrapping_sub(index as u16);
+        let data = value & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if page_cross { (data as u16) << 8 | (addr & 0xff) } else { addr };
+        self.mem_write(addr, data);
+    }
+
     fn set_register_a(&mut self, value: u8) {
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let value = self.mem_read(addr);
         self.set_register_a(value);
         if page_cross {
@@ -159,7 +371,7 @@ impl<'a> CPU<'a> {
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_x = value;
         self.update_zero_and_negative_flags(self.register_x);
@@ -169,7 +381,7 @@ impl<'a> CPU<'a> {
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_y = value;
         self.update_zero_and_negative_flags(self.register_y);
@@ -184,7 +396,7 @@ impl<'a> CPU<'a> {
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let data = self.mem_read(addr);
         self.set_register_a(data & self.register_a);
         if page_cross {
@@ -193,7 +405,7 @@ impl<'a> CPU<'a> {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let data = self.mem_read(addr);
         self.set_register_a(data ^ self.register_a);
         if page_cross {
@@ -202,7 +414,7 @@ impl<'a> CPU<'a> {
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let data = self.mem_read(addr);
         self.set_register_a(data | self.register_a);
         if page_cross {
@@ -231,11 +443,11 @@ impl<'a> CPU<'a> {
 
     fn inc(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        data = data.wrapping_add(1);
-        self.mem_write(addr, data);
-        self.update_zero_and_negative_flags(data);
-        data
+        let data = self.mem_read(addr);
+        let result = data.wrapping_add(1);
+        self.rmw_write(addr, data, result);
+        self.update_zero_and_negative_flags(result);
+        result
     }
 
     fn inx(&mut self) {
@@ -250,11 +462,11 @@ impl<'a> CPU<'a> {
 
     fn dec(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        data = data.wrapping_sub(1);
-        self.mem_write(addr, data);
-        self.update_zero_and_negative_flags(data);
-        data
+        let data = self.mem_read(addr);
+        let result = data.wrapping_sub(1);
+        self.rmw_write(addr, data, result);
+        self.update_zero_and_negative_flags(result);
+        result
     }
 
     fn dex(&mut self) {
@@ -267,7 +479,7 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_y);
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> RunStop {
         self.load(program);
         self.reset();
         self.program_counter = 0x0600;
@@ -278,19 +490,62 @@ impl<'a> CPU<'a> {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
+        // 電源投入時のPは$34と書かれることが多いが、ビット4(B)はレジスタには無く
+        // PHPやBRKで積んだときだけ1になる。レジスタとしては$24 (nestest.logも$24から始まる)
         self.stack_pointer = 0xfd;
-        self.status = 0b0010_0100;
+        self.status = UNUSED_FLAG | INTERRUPT_DISABLE_FLAG;
 
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
-        for i in 0..(program.len() as u16) {
-            self.mem_write(0x0600 + i, program[i as usize]);
+        self.load_at(&program, 0x0600);
+    }
+
+    // ROM領域($8000-)に置くとカートリッジのPRG-ROMを書き換える
+    pub fn load_at(&mut self, program: &[u8], addr: u16) {
+        for (i, byte) in program.iter().enumerate() {
+            self.bus.poke(addr.wrapping_add(i as u16), *byte);
         }
-        //self.mem_write_u16(0xFFFC, 0x0600);
-        //self.memory[0x0600..(0x0600 + program.len())].copy_from_slice(&program[..]);
-        //self.mem_write_u16(0xFFFC, 0x0600);
+    }
+
+    pub fn set_reset_vector(&mut self, addr: u16) {
+        self.bus.poke(0xfffc, (addr & 0xff) as u8);
+        self.bus.poke(0xfffd, (addr >> 8) as u8);
+    }
+
+    // リセット後に別の場所から始める (nestestの自動モードは$C000から)
+    // ほかのレジスタもまとめて変えるならset_state
+    pub fn set_pc(&mut self, addr: u16) {
+        self.program_counter = addr;
+    }
+
+    // レジスタだけ。メモリやPPUの状態は含まない
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            register_a: self.register_a,
+            register_x: self.register_x,
+            register_y: self.register_y,
+            status: self.status,
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+        }
+    }
+
+    pub fn set_state(&mut self, state: CpuState) {
+        self.register_a = state.register_a;
+        self.register_x = state.register_x;
+        self.register_y = state.register_y;
+        self.status = state.status;
+        self.program_counter = state.program_counter;
+        self.stack_pointer = state.stack_pointer;
+    }
+
+    // リードモディファイライト命令は、変更後の値の前に元の値を一度書き戻す
+    // (マッパーのレジスタや$2007では2回書き込んだことになる)
+    fn rmw_write(&mut self, addr: u16, old: u8, data: u8) {
+        self.mem_write(addr, old);
+        self.mem_write(addr, data);
     }
 
     fn set_carry_flag(&mut self) {
@@ -302,7 +557,7 @@ impl<'a> CPU<'a> {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let data = self.mem_read(addr);
 
         let a = self.register_a.clone();
@@ -329,7 +584,7 @@ impl<'a> CPU<'a> {
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let data = self.mem_read(addr);
 
         let a = self.register_a.clone();
@@ -357,6 +612,24 @@ impl<'a> CPU<'a> {
         }
     }
 
+    // デバッガ用。offset 0が最後に積んだ値で、数字が大きいほど古い。$0100-$01FFの中で回り込む
+    // 見るだけでオープンバスが変わらないよう、peekで読む
+    pub fn peek_stack(&mut self, offset: u8) -> u8 {
+        let addr = self.stack_pointer.wrapping_add(1).wrapping_add(offset);
+        self.bus.peek(0x0100 + addr as u16)
+    }
+
+    pub fn poke_stack(&mut self, offset: u8, data: u8) {
+        let addr = self.stack_pointer.wrapping_add(1).wrapping_add(offset);
+        self.mem_write(0x0100 + addr as u16, data);
+    }
+
+    // 今積まれている分 (SPの1つ上から$01FFまで) を新しい順に返す
+    pub fn stack_contents(&mut self) -> Vec<u8> {
+        let used = 0xff - self.stack_pointer;
+        (0..used).map(|offset| self.peek_stack(offset)).collect()
+    }
+
     fn stack_pop(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
         self.mem_read(0x0100 + self.stack_pointer as u16)
@@ -386,14 +659,17 @@ impl<'a> CPU<'a> {
         self.set_register_a(data);
     }
 
+    // スタックから戻すときBフラグは実在しないので捨て、未使用ビットは常に1
+    fn pull_status(&mut self) {
+        self.status = (self.stack_pop() & !BREAK_FLAG) | UNUSED_FLAG;
+    }
+
     fn plp(&mut self) {
-        self.status = self.stack_pop();
-        self.status = self.status & 0b1110_1111;
-        self.status = self.status | 0b0010_0000;
+        self.pull_status();
     }
 
     fn php(&mut self) {
-        let flag = self.status | 0b0011_0000;
+        let flag = self.status | BREAK_FLAG | UNUSED_FLAG;
         self.stack_push(flag);
     }
 
@@ -421,7 +697,7 @@ impl<'a> CPU<'a> {
     }
 
     fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, page_cross) = self.read_operand_address(mode);
         let data = self.mem_read(addr);
 
         if data <= compare_with {
@@ -467,7 +743,7 @@ impl<'a> CPU<'a> {
         } else {
             self.clear_carry_flag();
         }
-        self.mem_write(addr, data << 1);
+        self.rmw_write(addr, data, data << 1);
         self.update_zero_and_negative_flags(data);
         data
     }
@@ -490,14 +766,15 @@ impl<'a> CPU<'a> {
         } else {
             self.clear_carry_flag();
         }
-        self.mem_write(addr, data >> 1);
+        self.rmw_write(addr, data, data >> 1);
         self.update_zero_and_negative_flags(data);
         data
     }
 
     fn rol(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let old = self.mem_read(addr);
+        let mut data = old;
         let old_array = self.status & 0b0000_0001 == 1;
 
         if data >> 7 == 1 {
@@ -510,7 +787,7 @@ impl<'a> CPU<'a> {
         if old_array {
             data = data | 1;
         }
-        self.mem_write(addr, data);
+        self.rmw_write(addr, old, data);
         self.update_zero_and_negative_flags(data);
         data
     }
@@ -534,7 +811,8 @@ impl<'a> CPU<'a> {
 
     fn ror(&mut self, mode: &AddressingMode) -> u8 {
         let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+        let old = self.mem_read(addr);
+        let mut data = old;
         let old_array = self.status & 0b0000_0001 == 1;
 
         if data & 1 == 1 {
@@ -547,7 +825,7 @@ impl<'a> CPU<'a> {
         if old_array {
             data = data | 0b1000_0000;
         }
-        self.mem_write(addr, data);
+        self.rmw_write(addr, old, data);
         self.update_zero_and_negative_flags(data);
         data
     }
@@ -637,393 +915,511 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_a);
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    pub fn run(&mut self) -> RunStop {
+        self.run_with_callback(|_| {})
+    }
+
+    fn unknown_opcode(&mut self, code: u8) -> Option<RunStop> {
+        let pc = self.program_counter.wrapping_sub(1);
+        if self.strict {
+            Some(RunStop::UnknownOpcode { opcode: code, pc })
+        } else {
+            None
+        }
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> RunStop
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut Self),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPECODES_MAP;
-
+        let mut executed = 0;
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(MNI);
+            if self.instruction_budget.is_some_and(|budget| executed >= budget) {
+                return RunStop::BudgetExhausted;
+            }
+            executed += 1;
+            self.wait_while_halted();
+            self.poll_interrupts();
+            if let Some(stop) = self.check_opcode_breakpoint() {
+                return stop;
             }
             callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-
-            let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} is not recognized", code));
-
-            match code {
-                //LDA
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode),
-                //CLD
-                0xd8 => self.status = self.status & 0b1111_0111,
-                //CLI
-                0x58 => self.status = self.status & 0b1111_1011, 
-                //CLV
-                0xb8 => self.status = self.status & 0b1011_1111,
-                //CLC
-                0x18 => self.clear_carry_flag(),
-                //SEC
-                0x38 => self.set_carry_flag(),
-                //SEI
-                0x78 => self.status = self.status | 0b0000_0100,
-                //SED
-                0xf8 => self.status = self.status | 0b0000_1000,
-                //PHA
-                0x48 => self.stack_push(self.register_a),
-                //PLA
-                0x68 => self.pla(),
-                //PHP
-                0x08 => self.php(),
-                //PLP
-                0x28 => self.plp(),
-                //ADC
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                //SBC
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
-                //AND
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
-                //EOR
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
-                //ORA
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
-                //LSR
-                0x4a => self.lsr_accumulator(),
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                },
-                //ASL
-                0x0a => self.asl_accumulator(),
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                },
-                //ROL
-                0x2a => self.rol_accumulator(),
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                },
-                //ROR
-                0x6a => self.ror_accumulator(),
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                },
-                //INC
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                },
-                //INX
-                0xE8 => self.inx(),
-                //INY
-                0xc8 => self.iny(),
-                //DEC
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                },
-                //DEX
-                0xca => self.dex(),
-                //DEY
-                0x88 => self.dey(),
-                //CMP
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => self.compare(&opcode.mode, self.register_a),
-                //CPY
-                0xc0 | 0xc4 | 0xcc => self.compare(&opcode.mode, self.register_y),
-                //CPX
-                0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
-                //JMP Absolute
-                0x4c => {
-                    let mem_addr = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_addr;
-                },
-                //JMP Indirect_X
-                0x6c => {
-                    let mem_addr = self.mem_read_u16(self.program_counter);
-                    let indirect_ref = if mem_addr & 0x00ff == 0x00ff {
-                        let lo = self.mem_read(mem_addr);
-                        let hi = self.mem_read(mem_addr & 0xff00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_addr)
-                    };
-                    self.program_counter = indirect_ref;
-                },
-                //JSR
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_addr = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_addr
-                },
-                //RTS
-                0x60 => self.program_counter = self.stack_pop_u16() + 1,
-                //RTI
-                0x40 => {
-                    self.status = self.stack_pop();
-                    self.status = self.status & 0b1110_1111;
-                    self.status = self.status | 0b0010_0000;
-                    self.program_counter = self.stack_pop_u16();
-
-                },
-                //BNE
-                0xd0 => self.branch(self.status & 0b0000_0010 == 0),
-                //BVS
-                0x70 => self.branch(self.status & 0b0100_0000 != 0),
-                //BVC
-                0x50 => self.branch(self.status & 0b0100_0000 == 0),
-                //BPL
-                0x10 => self.branch(self.status & 0b1000_0000 == 0),
-                //BMI
-                0x30 => self.branch(self.status & 0b1000_0000 != 0),
-                //BEQ
-                0xf0 => self.branch(self.status & 0b0000_0010 != 0),
-                //BCS
-                0xb0 => self.branch(self.status & 0b0000_0001 != 0),
-                //BCC
-                0x90 => self.branch(self.status & 0b0000_0001 == 0),
-                //BIT
-                0x24 | 0x2c => self.bit(&opcode.mode),
-                //STA
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
-                //STX
-                0x86 | 0x96 | 0x8e => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_x);
-                },
-                //STY
-                0x84 | 0x94 | 0x8c => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, self.register_y);
-                },
-                //LDX
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode),
-                //LDY
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode),
-                //TAX
-                0xaa => self.tax(),
-                //TAY
-                0xa8 => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                },
-                //TSX
-                0xba =>{
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                },
-                //TXA
-                0x8a => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                },
-                //TXS
-                0x9a => self.stack_pointer = self.register_x,
-                //TYA
-                0x98 => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                },
-                //NOP
-                0xea => {},
-                //BRK
-                0x00 => return,
-                //unofficial opcodes
-                //NOPS
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let (_, page_cross) = self.get_operand_address(&opcode.mode);
-                    if page_cross {
-                        self.bus.tick(1)
-                    }
-                },
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {},
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {},
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {},
-                //LAX
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    self.register_x = self.register_a;
-                },
-                //SAX
-                0x87 | 0x97 | 0x8f | 0x83 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.register_a & self.register_x;
-                    self.mem_write(addr, data);
-                },
-                //SBC
-                0xeb => self.sbc(&opcode.mode),
-                //DCP
-                0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xd3 | 0xc3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data.wrapping_add(1);
-                    self.mem_write(addr, data);
-
-                    if data <= self.register_a {
-                        self.status = self.status | 0x0000_0001;
-                    }
-                    self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
-                },
-                //ISB
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => self.unofficial_isb(&opcode.mode),
-                //SLO
-                0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => self.unofficial_slo(&opcode.mode),
-                //RLA
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => self.unofficial_rla(&opcode.mode),
-                //SRE
-                0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => self.unofficial_sre(&opcode.mode),
-                //RRA
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.unofficial_rra(&opcode.mode),
-                //AXS
-                0xcb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    let x_and_a = self.register_x & self.register_a;
-                    let result = x_and_a.wrapping_sub(data);
-
-                    if data <= x_and_a {
-                        self.status = self.status | 0b0000_0001;
-                    }
-                    self.update_zero_and_negative_flags(result);
-
-                    self.register_x = result;
-                },
-                //ARR
-                0x6b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    self.ror_accumulator();
-
-                    let result = self.register_a;
-                    let bit_5 = (result >> 5) & 1;
-                    let bit_6 = (result >> 6) & 1;
-
-                    if bit_6 == 1 {
-                        self.status = self.status | 0b0000_0001;
-                    } else {
-                        self.status = self.status & 0b1111_1110;
-                    }
-
-                    if bit_5 ^ bit_6 == 1 {
-                        self.status = self.status | 0b0100_0000;
-                    } else {
-                        self.status = self.status & 0b1011_1111;
-                    }
-
-                    self.update_zero_and_negative_flags(result);
-                },
-                //ANC
-                0x0b | 0x2b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    if self.status == 0b1000_0000 {
-                        self.status = self.status | 0b0000_0001;
-                    } else {
-                        self.status = self.status & 0b1111_1110;
-                    }
-                },
-                //ALR
-                0x4b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    self.lsr_accumulator();
-                },
-                //LXA
-                0xab => {
-                    self.lda(&opcode.mode);
-                    self.tax();
-                },
-                //XAA
-                0x8b => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                },
-                //LAS
-                0x9b => {
-                    let data = self.register_a & self.register_x;
-                    self.stack_pointer = data;
-                    let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
-
-                    let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                    self.mem_write(mem_address, data);
-                },
-                //AHX I Y
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.program_counter);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data);
-                },
-                //AHX A X
-                0x9f => {
-                    let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data);
-                },
-                //SHX
-                0x9e => {
-                    let mem_address = self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                    let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data);
-                }
-                _ => todo!()
+            if let Some(stop) = self.execute() {
+                return stop;
             }
+        }
+    }
+
+    // 割り込みを処理してから1命令だけ実行する
+    pub fn step(&mut self) -> Option<RunStop> {
+        self.resume_from_break = None;
+        self.wait_while_halted();
+        self.poll_interrupts();
+        self.execute()
+    }
 
-            self.bus.tick(opcode.cycles);
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+    fn check_opcode_breakpoint(&mut self) -> Option<RunStop> {
+        // 止まった命令から再開できるように、そのアドレスでは1回だけ止まらない
+        // 割り込みやPCの書き換えで別の所から始まったときは、最初の命令でも止まる
+        let resume_from = self.resume_from_break.take();
+        self.opcode_breakpoints.as_ref()?;
+        let pc = self.program_counter;
+        if resume_from == Some(pc) {
+            return None;
+        }
+        // $2002などを読むとフラグが変わるので、mem_readではなくpeekで見る
+        let opcode = self.bus.peek(pc);
+        if self.opcode_breakpoints.as_ref().is_some_and(|breakpoints| breakpoints[opcode as usize]) {
+            self.resume_from_break = Some(pc);
+            Some(RunStop::Breakpoint { opcode, pc })
+        } else {
+            None
+        }
+    }
+
+    // RDYが下がっている間は命令を実行せず、バス(PPU・APU)の時間だけ進める
+    fn wait_while_halted(&mut self) {
+        for _ in 0..self.bus.take_halt_cycles() {
+            self.bus.tick(1);
+        }
+    }
+
+    fn poll_interrupts(&mut self) {
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt(MNI);
+        } else if self.bus.irq_pending() && self.status & INTERRUPT_DISABLE_FLAG == 0 {
+            self.interrupt(IRQ);
+        }
+    }
+
+    fn execute(&mut self) -> Option<RunStop> {
+        if self.profiler.is_none() {
+            return self.execute_instruction();
+        }
+
+        let pc = self.program_counter;
+        let start = self.bus.cycles();
+        let stop = self.execute_instruction();
+        let cycles = self.bus.cycles() - start;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, cycles);
+        }
+        stop
+    }
+
+    fn execute_instruction(&mut self) -> Option<RunStop> {
+        let opcodes = self.opcode_table;
+
+        self.call_trace_hook();
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+        if let Some(counts) = self.opcode_counts.as_mut() {
+            counts[code as usize] += 1;
+        }
+
+        // 256命令すべてを下のmatchで網羅しているので、ここで見つからないのはテーブルの抜け
+        let opcode = match opcodes.get(&code) {
+            Some(opcode) => opcode,
+            None => {
+                if let Some(stop) = self.unknown_opcode(code) {
+                    return Some(stop);
+                }
+                self.bus.tick(2);
+                return None;
             }
+        };
+
+        match code {
+            //LDA
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode),
+            //CLD
+            0xd8 => self.status = self.status & 0b1111_0111,
+            //CLI
+            0x58 => self.status = self.status & 0b1111_1011, 
+            //CLV
+            0xb8 => self.status = self.status & 0b1011_1111,
+            //CLC
+            0x18 => self.clear_carry_flag(),
+            //SEC
+            0x38 => self.set_carry_flag(),
+            //SEI
+            0x78 => self.status = self.status | 0b0000_0100,
+            //SED
+            0xf8 => self.status = self.status | 0b0000_1000,
+            //PHA
+            0x48 => self.stack_push(self.register_a),
+            //PLA
+            0x68 => self.pla(),
+            //PHP
+            0x08 => self.php(),
+            //PLP
+            0x28 => self.plp(),
+            //ADC
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
+            //SBC
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
+            //AND
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
+            //EOR
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
+            //ORA
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
+            //LSR
+            0x4a => self.lsr_accumulator(),
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            },
+            //ASL
+            0x0a => self.asl_accumulator(),
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            },
+            //ROL
+            0x2a => self.rol_accumulator(),
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            },
+            //ROR
+            0x6a => self.ror_accumulator(),
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            },
+            //INC
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            },
+            //INX
+            0xE8 => self.inx(),
+            //INY
+            0xc8 => self.iny(),
+            //DEC
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            },
+            //DEX
+            0xca => self.dex(),
+            //DEY
+            0x88 => self.dey(),
+            //CMP
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => self.compare(&opcode.mode, self.register_a),
+            //CPY
+            0xc0 | 0xc4 | 0xcc => self.compare(&opcode.mode, self.register_y),
+            //CPX
+            0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
+            //JMP Absolute
+            0x4c => {
+                let mem_addr = self.mem_read_u16(self.program_counter);
+                self.program_counter = mem_addr;
+            },
+            //JMP Indirect_X
+            0x6c => {
+                let mem_addr = self.mem_read_u16(self.program_counter);
+                let indirect_ref = if mem_addr & 0x00ff == 0x00ff {
+                    let lo = self.mem_read(mem_addr);
+                    let hi = self.mem_read(mem_addr & 0xff00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_addr)
+                };
+                self.program_counter = indirect_ref;
+            },
+            //JSR
+            0x20 => {
+                self.stack_push_u16(self.program_counter + 2 - 1);
+                let target_addr = self.mem_read_u16(self.program_counter);
+                self.program_counter = target_addr
+            },
+            //RTS
+            0x60 => self.program_counter = self.stack_pop_u16() + 1,
+            //RTI
+            0x40 => {
+                self.pull_status();
+                self.program_counter = self.stack_pop_u16();
+            },
+            //BNE
+            0xd0 => self.branch(self.status & 0b0000_0010 == 0),
+            //BVS
+            0x70 => self.branch(self.status & 0b0100_0000 != 0),
+            //BVC
+            0x50 => self.branch(self.status & 0b0100_0000 == 0),
+            //BPL
+            0x10 => self.branch(self.status & 0b1000_0000 == 0),
+            //BMI
+            0x30 => self.branch(self.status & 0b1000_0000 != 0),
+            //BEQ
+            0xf0 => self.branch(self.status & 0b0000_0010 != 0),
+            //BCS
+            0xb0 => self.branch(self.status & 0b0000_0001 != 0),
+            //BCC
+            0x90 => self.branch(self.status & 0b0000_0001 == 0),
+            //BIT
+            0x24 | 0x2c => self.bit(&opcode.mode),
+            //STA
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
+            //STX
+            0x86 | 0x96 | 0x8e => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_x);
+            },
+            //STY
+            0x84 | 0x94 | 0x8c => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, self.register_y);
+            },
+            //LDX
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode),
+            //LDY
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode),
+            //TAX
+            0xaa => self.tax(),
+            //TAY
+            0xa8 => {
+                self.register_y = self.register_a;
+                self.update_zero_and_negative_flags(self.register_y);
+            },
+            //TSX
+            0xba =>{
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            },
+            //TXA
+            0x8a => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+            },
+            //TXS
+            0x9a => self.stack_pointer = self.register_x,
+            //TYA
+            0x98 => {
+                self.register_a = self.register_y;
+                self.update_zero_and_negative_flags(self.register_a);
+            },
+            //NOP
+            0xea => {},
+            //BRK
+            0x00 => return Some(RunStop::Break),
+            //unofficial opcodes
+            //NOPS
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let (_, page_cross) = self.read_operand_address(&opcode.mode);
+                if page_cross {
+                    self.bus.tick(1)
+                }
+            },
+            //KIL
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                if self.jam_on_kil {
+                    let pc = program_counter_state.wrapping_sub(1);
+                    self.program_counter = pc;
+                    return Some(RunStop::Jammed { opcode: code, pc });
+                }
+            },
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {},
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {},
+            //LAX
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data;
+                self.update_zero_and_negative_flags(self.register_a);
+                self.register_x = self.register_a;
+            },
+            //SAX
+            0x87 | 0x97 | 0x8f | 0x83 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.register_a & self.register_x;
+                self.mem_write(addr, data);
+            },
+            //SBC
+            0xeb => self.sbc(&opcode.mode),
+            //DCP
+            0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xd3 | 0xc3 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let old = self.mem_read(addr);
+                let data = old.wrapping_add(1);
+                self.rmw_write(addr, old, data);
+
+                if data <= self.register_a {
+                    self.status = self.status | 0x0000_0001;
+                }
+                self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            },
+            //ISB
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => self.unofficial_isb(&opcode.mode),
+            //SLO
+            0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => self.unofficial_slo(&opcode.mode),
+            //RLA
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => self.unofficial_rla(&opcode.mode),
+            //SRE
+            0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => self.unofficial_sre(&opcode.mode),
+            //RRA
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.unofficial_rra(&opcode.mode),
+            //AXS
+            0xcb => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                let x_and_a = self.register_x & self.register_a;
+                let result = x_and_a.wrapping_sub(data);
+
+                if data <= x_and_a {
+                    self.status = self.status | 0b0000_0001;
+                }
+                self.update_zero_and_negative_flags(result);
+
+                self.register_x = result;
+            },
+            //ARR
+            0x6b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+                self.ror_accumulator();
+
+                let result = self.register_a;
+                let bit_5 = (result >> 5) & 1;
+                let bit_6 = (result >> 6) & 1;
+
+                if bit_6 == 1 {
+                    self.status = self.status | 0b0000_0001;
+                } else {
+                    self.status = self.status & 0b1111_1110;
+                }
+
+                if bit_5 ^ bit_6 == 1 {
+                    self.status = self.status | 0b0100_0000;
+                } else {
+                    self.status = self.status & 0b1011_1111;
+                }
+
+                self.update_zero_and_negative_flags(result);
+            },
+            //ANC
+            0x0b | 0x2b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+                if self.status == 0b1000_0000 {
+                    self.status = self.status | 0b0000_0001;
+                } else {
+                    self.status = self.status & 0b1111_1110;
+                }
+            },
+            //ALR
+            0x4b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+                self.lsr_accumulator();
+            },
+            //LXA
+            0xab => {
+                self.lda(&opcode.mode);
+                self.tax();
+            },
+            //XAA
+            0x8b => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+            },
+            //TAS
+            0x9b => {
+                self.stack_pointer = self.register_a & self.register_x;
+                self.unstable_store(&opcode.mode, self.stack_pointer);
+            },
+            //AHX
+            0x93 | 0x9f => {
+                self.unstable_store(&opcode.mode, self.register_a & self.register_x);
+            },
+            //SHX
+            0x9e => {
+                self.unstable_store(&opcode.mode, self.register_x);
+            },
+            //SHY
+            0x9c => {
+                self.unstable_store(&opcode.mode, self.register_y);
+            },
+            //LAS
+            0xbb => {
+                let (addr, page_cross) = self.read_operand_address(&opcode.mode);
+                let data = self.mem_read(addr) & self.stack_pointer;
+                self.register_a = data;
+                self.register_x = data;
+                self.stack_pointer = data;
+                self.update_zero_and_negative_flags(data);
+                if page_cross {
+                    self.bus.tick(1)
+                }
+            },
+        }
 
+        self.bus.tick(opcode.cycles);
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
         }
+        None
     }
 
     fn interrupt(&mut self, interrupt: Interrupt) {
         self.stack_push_u16(self.program_counter);
-        let mut flag = self.status.clone();
-        if interrupt.b_flag_mask & 0b010000 == 1 {
-            flag = flag | 0b0001_0000;
-        } else {
-            flag = flag & 0b1110_1111;
-        }
-        if interrupt.b_flag_mask & 0b100000 == 1 {
-            flag = flag | 0b0010_0000;
-        } else {
-            flag = flag & 0b1101_1111;
-        }
+        let flag = (self.status & !(BREAK_FLAG | UNUSED_FLAG))
+            | (interrupt.b_flag_mask & (BREAK_FLAG | UNUSED_FLAG));
 
         self.stack_push(flag);
-        self.status = self.status | 0b0000_0100;
+        self.status |= INTERRUPT_DISABLE_FLAG;
 
         self.bus.tick(interrupt.cpu_cycles);
         self.program_counter = self.mem_read_u16(interrupt.vector_addr);
     }
 }
 
+// セーブステートはNESのBusをつないだときだけ
+impl<'a> CPU<'a, Bus<'a>> {
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.register_a);
+        w.write_u8(self.register_x);
+        w.write_u8(self.register_y);
+        w.write_u8(self.status);
+        w.write_u16(self.program_counter);
+        w.write_u8(self.stack_pointer);
+        self.bus.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.register_a = r.read_u8()?;
+        self.register_x = r.read_u8()?;
+        self.register_y = r.read_u8()?;
+        self.status = r.read_u8()?;
+        self.program_counter = r.read_u16()?;
+        self.stack_pointer = r.read_u8()?;
+        self.bus.load_state(r)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::emu::cartridge::test;
+    use crate::joypad::Joypad;
     use crate::ppu_emu::ppu::NesPPU;
+    use crate::emu::bus::PowerOnState;
+    use crate::emu::profiler;
+    use crate::emu::harness::CpuTestHarness;
+    use crate::emu::mapper::test::TestMapper;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
-        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {});
+        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {}).unwrap();
         let mut cpu = CPU::new(bus);
         cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
         assert_eq!(cpu.register_a, 5);
@@ -1033,7 +1429,7 @@ mod test {
 
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
-        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {});
+        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {}).unwrap();
         let mut cpu = CPU::new(bus);
         cpu.register_a = 10;
         cpu.load_and_run(vec![0xa9, 0x0A,0xaa, 0x00]);
@@ -1043,7 +1439,7 @@ mod test {
 
     #[test]
     fn test_5_ops_working_together() {
-        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {});
+        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {}).unwrap();
         let mut cpu = CPU::new(bus);
         cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
 
@@ -1052,7 +1448,7 @@ mod test {
 
     #[test]
     fn test_inx_overflow() {
-        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {});
+        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {}).unwrap();
         let mut cpu = CPU::new(bus);
         cpu.load_and_run(vec![0xa2, 0xff, 0xe8, 0xe8, 0x00]);
 
@@ -1061,7 +1457,7 @@ mod test {
 
     #[test]
     fn test_lda_from_memory() {
-        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {});
+        let bus = Bus::new(test::test_rom(), |ppu: &NesPPU, &mut Joypad| {}).unwrap();
         let mut cpu = CPU::new(bus);
         cpu.mem_write(0x10, 0x55);
 
@@ -1069,4 +1465,805 @@ mod test {
 
         assert_eq!(cpu.register_a, 0x55);
     }
+
+    #[test]
+    fn test_brk_stops_run() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load(vec![0xe8, 0x00]);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.run(), RunStop::Break);
+    }
+
+    // $9Cが抜けた命令表を使うCPU
+    fn cpu_without_9c() -> CPU<'static> {
+        let mut table = opcodes::OPECODES_MAP.clone();
+        table.remove(&0x9c);
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.opcode_table = Box::leak(Box::new(table));
+        cpu
+    }
+
+    #[test]
+    fn test_unknown_opcode_permissive_is_nop() {
+        let mut cpu = cpu_without_9c();
+
+        // $9C / INX / BRK。$9Cは1バイトの命令として読み飛ばされる
+        assert_eq!(cpu.load_and_run(vec![0x9c, 0xe8, 0x00]), RunStop::Break);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_unknown_opcode_strict_stops() {
+        let mut cpu = cpu_without_9c();
+        cpu.set_strict(true);
+
+        assert_eq!(
+            cpu.load_and_run(vec![0x9c, 0xe8, 0x00]),
+            RunStop::UnknownOpcode { opcode: 0x9c, pc: 0x0600 }
+        );
+        assert_eq!(cpu.register_x, 0);
+    }
+
+    #[test]
+    fn test_every_opcode_is_decoded() {
+        // BRK以外の全命令を1回ずつ実行してもpanicしないこと
+        for code in 0x01..=0xffu8 {
+            let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+            let mut cpu = CPU::new(bus);
+            cpu.set_strict(true);
+            cpu.load(vec![code, 0x00, 0x00, 0x00]);
+            cpu.reset();
+            cpu.program_counter = 0x0600;
+
+            // 調べる命令の次からは$0610のBRKに飛ばす
+            let stop = cpu.run_with_callback(|cpu| {
+                if cpu.program_counter != 0x0600 {
+                    cpu.program_counter = 0x0610;
+                }
+            });
+            assert_eq!(stop, RunStop::Break, "opcode {:02x}", code);
+        }
+    }
+
+    #[test]
+    fn test_las() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x0210, 0b1010_1111);
+        cpu.load(vec![0xa0, 0x10, 0xbb, 0x00, 0x02, 0x00]);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        cpu.stack_pointer = 0b1111_0101;
+        cpu.run();
+
+        assert_eq!(cpu.register_a, 0b1010_0101);
+        assert_eq!(cpu.register_x, 0b1010_0101);
+        assert_eq!(cpu.stack_pointer, 0b1010_0101);
+        assert!(cpu.status & 0b1000_0000 != 0);
+    }
+
+    // レジスタに値を入れてから比較命令を実行し、ステータスを返す
+    fn compare_status(load: u8, compare: u8, value: u8, with: u8) -> u8 {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![load, value, compare, with, 0x00]);
+        cpu.status
+    }
+
+    const C: u8 = 0b0000_0001;
+    const Z: u8 = 0b0000_0010;
+    const N: u8 = 0b1000_0000;
+    const D: u8 = 0b0000_1000;
+
+    fn flags(status: u8) -> u8 {
+        status & (C | Z | N)
+    }
+
+    // NESの2A03にはBCDがないので、Dフラグは覚えるだけで演算には効かない
+    #[test]
+    fn test_adc_ignores_decimal_flag() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![0xf8, 0x18, 0xa9, 0x09, 0x69, 0x01, 0x00]); // SED CLC LDA #$09 ADC #$01
+        assert_eq!(cpu.register_a, 0x0a);
+        assert_eq!(cpu.status & D, D);
+
+        cpu.load_and_run(vec![0xf8, 0x18, 0xa9, 0x58, 0x69, 0x46, 0x00]); // SED CLC LDA #$58 ADC #$46
+        assert_eq!(cpu.register_a, 0x9e);
+        assert_eq!(cpu.status & C, 0);
+    }
+
+    #[test]
+    fn test_sbc_ignores_decimal_flag() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![0xf8, 0x38, 0xa9, 0x10, 0xe9, 0x01, 0x00]); // SED SEC LDA #$10 SBC #$01
+        assert_eq!(cpu.register_a, 0x0f);
+        assert_eq!(cpu.status & C, C);
+        assert_eq!(cpu.status & D, D);
+    }
+
+    // (キャリー, A, オペランド) -> (結果, キャリー)
+    fn sbc(carry: bool, a: u8, data: u8) -> (u8, u8) {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        let set_carry = if carry { 0x38 } else { 0x18 };
+        cpu.load_and_run(vec![set_carry, 0xa9, a, 0xe9, data, 0x00]);
+        (cpu.register_a, cpu.status & C)
+    }
+
+    // キャリーは借りの反転。クリアされていると1余分に引く
+    #[test]
+    fn test_sbc_borrow() {
+        assert_eq!(sbc(true, 0x50, 0x10), (0x40, C));
+        assert_eq!(sbc(false, 0x50, 0x10), (0x3f, C));
+        assert_eq!(sbc(true, 0x50, 0x50), (0x00, C));
+        assert_eq!(sbc(false, 0x50, 0x50), (0xff, 0));
+        assert_eq!(sbc(true, 0x10, 0x50), (0xc0, 0));
+        assert_eq!(sbc(false, 0x00, 0x00), (0xff, 0));
+        assert_eq!(sbc(true, 0x00, 0x80), (0x80, 0));
+        assert_eq!(sbc(false, 0x00, 0xff), (0x00, 0));
+        assert_eq!(sbc(true, 0xff, 0xff), (0x00, C));
+    }
+
+    // 非公式の$EBはSBC #immと同じ。フラグもすべて一致する
+    #[test]
+    fn test_unofficial_sbc_eb_matches_e9() {
+        let op = opcodes::OPECODES_MAP[&0xeb];
+        assert!(matches!(op.mode, AddressingMode::Immediate));
+        assert_eq!((op.len, op.cycles), (2, 2));
+
+        for (carry, a, data) in [(true, 0x50, 0x10), (false, 0x50, 0x50), (true, 0x10, 0x50), (true, 0x80, 0x01), (false, 0x7f, 0xff)] {
+            let status = if carry { 0x25 } else { 0x24 };
+            let run = |opcode: u8| {
+                let cpu = CpuTestHarness::new().register_a(a).status(status).run(&[opcode, data, 0x00]);
+                (cpu.register_a, cpu.status)
+            };
+            assert_eq!(run(0xeb), run(0xe9), "carry {} A {:02x} - {:02x}", carry, a, data);
+        }
+    }
+
+    #[test]
+    fn test_sed_cld() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![0xf8, 0x00]);
+        assert_eq!(cpu.status & D, D);
+        cpu.load_and_run(vec![0xf8, 0xd8, 0x00]);
+        assert_eq!(cpu.status & D, 0);
+    }
+
+    #[test]
+    fn test_cmp_equal() {
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x40, 0x40)), C | Z);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0xc0, 0xc0)), C | Z);
+    }
+
+    #[test]
+    fn test_cmp_less_than() {
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x10, 0x20)), N);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x80, 0xff)), N);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x10, 0x90)), N);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x10, 0x91)), 0);
+    }
+
+    #[test]
+    fn test_cmp_greater_than() {
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x20, 0x10)), C);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x90, 0x10)), C | N);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0xff, 0x80)), C);
+    }
+
+    #[test]
+    fn test_cmp_zero_boundaries() {
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x00, 0x00)), C | Z);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x00, 0x01)), N);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x01, 0x00)), C);
+        assert_eq!(flags(compare_status(0xa9, 0xc9, 0x80, 0x00)), C | N);
+    }
+
+    #[test]
+    fn test_cpx_cpy() {
+        // LDX/CPX
+        assert_eq!(flags(compare_status(0xa2, 0xe0, 0x40, 0x40)), C | Z);
+        assert_eq!(flags(compare_status(0xa2, 0xe0, 0x10, 0x20)), N);
+        assert_eq!(flags(compare_status(0xa2, 0xe0, 0x90, 0x10)), C | N);
+        // LDY/CPY
+        assert_eq!(flags(compare_status(0xa0, 0xc0, 0x40, 0x40)), C | Z);
+        assert_eq!(flags(compare_status(0xa0, 0xc0, 0x00, 0x01)), N);
+        assert_eq!(flags(compare_status(0xa0, 0xc0, 0xff, 0x80)), C);
+    }
+
+    #[test]
+    fn test_dmc_dma_counts_in_cpu_cycles() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![
+            0xa9, 0x0f, // LDA #$0f
+            0x8d, 0x10, 0x40, // STA $4010
+            0xa9, 0x00, // LDA #$00
+            0x8d, 0x12, 0x40, // STA $4012
+            0x8d, 0x13, 0x40, // STA $4013
+            0xa9, 0x10, // LDA #$10
+            0x8d, 0x15, 0x40, // STA $4015
+            0x00,
+        ]);
+
+        // LDA x3 (2) + STA x4 (4) + DMC DMA (4)
+        assert_eq!(cpu.cycles(), 3 * 2 + 4 * 4 + 4);
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        let traced = Rc::new(RefCell::new(vec![]));
+        let sink = traced.clone();
+        cpu.set_trace_hook(Box::new(move |info: &TraceInfo| sink.borrow_mut().push(info.clone())));
+        cpu.load_and_run(vec![0xa2, 0x05, 0xe8, 0x8d, 0x00, 0x02, 0x00]);
+
+        let traced = traced.borrow();
+        assert_eq!(traced.len(), 4);
+        assert_eq!(traced.iter().map(|t| t.pc).collect::<Vec<_>>(), vec![0x0600, 0x0602, 0x0603, 0x0606]);
+        assert_eq!(traced[0].bytes(), &[0xa2, 0x05]);
+        assert_eq!(traced[2].bytes(), &[0x8d, 0x00, 0x02]);
+        assert_eq!(traced[3].bytes(), &[0x00]);
+        assert_eq!(traced[2].register_x, 0x06);
+        assert_eq!(traced[1].cycles, 2);
+        assert_eq!(traced[3].cycles, 2 + 2 + 4);
+    }
+
+    #[test]
+    fn test_load_at_rom_address() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_at(&[0xa9, 0x07, 0x00], 0xc000);
+        cpu.set_reset_vector(0xc000);
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0xc000);
+
+        assert_eq!(cpu.run(), RunStop::Break);
+        assert_eq!(cpu.register_a, 0x07);
+    }
+
+    #[test]
+    fn test_profiler() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        assert!(cpu.profile_report().is_empty());
+
+        // $0600: JSR $0700 / $0700: LDX #$00 / INX / BNE $0702 / RTS
+        cpu.load_at(&[0x20, 0x00, 0x07, 0x00], 0x0600);
+        cpu.load_at(&[0xa2, 0x00, 0xe8, 0xd0, 0xfd, 0x60], 0x0700);
+        cpu.set_profiler_range_size(Some(profiler::PAGE));
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        cpu.run();
+
+        let report = cpu.profile_report();
+        // LDX 2 + (INX 2 + BNE 3) * 255 + (INX 2 + BNE 2) + RTS 6
+        assert_eq!(report[0], ProfileEntry { start: 0x0700, end: 0x07ff, cycles: 2 + 5 * 255 + 4 + 6 });
+        // JSR 6 + BRK
+        assert_eq!(report[1].start, 0x0600);
+        assert!(report[1].cycles >= 6);
+        assert_eq!(report.len(), 2);
+    }
+
+    // addrに置いたBNEを1命令実行して、かかったサイクル数と飛び先を返す
+    fn bne_cycles(addr: u16, offset: u8, taken: bool) -> (usize, u16) {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_at(&[0xd0, offset], addr);
+        cpu.program_counter = addr;
+        cpu.status = if taken { 0 } else { Z };
+        let start = cpu.cycles();
+        cpu.step();
+        (cpu.cycles() - start, cpu.program_counter)
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        assert_eq!(bne_cycles(0x0600, 0x05, false), (2, 0x0602));
+        assert_eq!(bne_cycles(0x0600, 0x05, true), (3, 0x0607));
+        assert_eq!(bne_cycles(0x06fd, 0x05, true), (4, 0x0704));
+        assert_eq!(bne_cycles(0x0700, 0xfc, true), (4, 0x06fe));
+        // ページをまたぐかは分岐命令の次の命令のアドレスと比べる
+        assert_eq!(bne_cycles(0x06fe, 0x05, true), (3, 0x0705));
+        assert_eq!(bne_cycles(0x06fe, 0xfe, true), (4, 0x06fe));
+    }
+
+    #[test]
+    fn test_zero_page_x_y_wraps() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x10, 0xf8);
+        cpu.register_x = 0x10;
+        cpu.register_y = 0xff;
+        assert_eq!(cpu.get_absolute_address(&AddressingMode::ZeroPage_X, 0x10), (0x0008, false));
+        assert_eq!(cpu.get_absolute_address(&AddressingMode::ZeroPage_Y, 0x10), (0x00f7, false));
+
+        // STA $F8,X / LDX $F0,Y
+        cpu.mem_write(0x0010, 0x77);
+        cpu.load_and_run(vec![0xa2, 0x10, 0xa9, 0x55, 0x95, 0xf8, 0xa0, 0x20, 0xb6, 0xf0, 0x00]);
+        assert_eq!(cpu.mem_read(0x0008), 0x55);
+        assert_eq!(cpu.mem_read(0x0108), 0x00);
+        assert_eq!(cpu.register_x, 0x77);
+    }
+
+    #[test]
+    fn test_jsr_rts_returns_after_jsr() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        let traced = Rc::new(RefCell::new(vec![]));
+        let sink = traced.clone();
+        cpu.set_trace_hook(Box::new(move |info: &TraceInfo| sink.borrow_mut().push((info.pc, info.stack_pointer))));
+
+        cpu.load_and_run(vec![
+            0x20, 0x07, 0x06, // $0600 JSR $0607
+            0xe8,             // $0603 INX
+            0x00,             // $0604 BRK
+            0x00, 0x00,
+            0xa9, 0x42,       // $0607 LDA #$42
+            0x60,             // $0609 RTS
+        ]);
+
+        // JSRは戻り先-1 ($0602) を積む
+        assert_eq!(cpu.mem_read(0x01fd), 0x06);
+        assert_eq!(cpu.mem_read(0x01fc), 0x02);
+        assert_eq!(
+            *traced.borrow(),
+            vec![(0x0600, 0xfd), (0x0607, 0xfb), (0x0609, 0xfb), (0x0603, 0xfd), (0x0604, 0xfd)]
+        );
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_opcode_histogram() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![0xa9, 0x01, 0x00]);
+        assert_eq!(cpu.opcode_histogram(), [0; 256]);
+
+        cpu.set_opcode_histogram_enabled(true);
+        cpu.load_and_run(vec![0xa9, 0x01, 0xa9, 0x02, 0xa5, 0x10, 0x00]);
+        let histogram = cpu.opcode_histogram();
+        assert_eq!(histogram[0xa9], 2);
+        assert_eq!(histogram[0xa5], 1);
+        assert_eq!(histogram[0x00], 1);
+        assert_eq!(histogram.iter().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn test_state_and_set_state() {
+        let mut cpu = CpuTestHarness::new()
+            .register_x(0x12)
+            .register_y(0x34)
+            .run(&[0xa9, 0x80, 0x48, 0x00]); // LDA #$80 / PHA
+        let state = cpu.state();
+        assert_eq!(
+            state,
+            CpuState {
+                register_a: 0x80,
+                register_x: 0x12,
+                register_y: 0x34,
+                status: 0b1010_0100,
+                program_counter: 0x0604,
+                stack_pointer: 0xfc,
+            }
+        );
+
+        cpu.load_at(&[0xa2, 0x00, 0xe8, 0x68, 0x00], 0x0604); // LDX #$00 / INX / PLA
+        cpu.run();
+        assert_ne!(cpu.state(), state);
+
+        cpu.set_state(state);
+        assert_eq!(cpu.state(), state);
+        assert_eq!((cpu.register_a, cpu.register_x, cpu.stack_pointer), (0x80, 0x12, 0xfc));
+    }
+
+    #[test]
+    fn test_halt_stalls_without_executing() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load(vec![0xe8, 0xe8, 0x00]); // INX / INX
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+
+        cpu.bus_mut().halt(100);
+        let start = cpu.cycles();
+        cpu.step();
+        // 止まっていた100サイクルのあとにINXを1回だけ実行する
+        assert_eq!(cpu.cycles() - start, 100 + 2);
+        assert_eq!((cpu.register_x, cpu.program_counter), (1, 0x0601));
+
+        // OAM DMAは513か514サイクル止める
+        cpu.mem_write(0x4014, 0x02);
+        let start = cpu.cycles();
+        cpu.step();
+        assert!([513 + 2, 514 + 2].contains(&(cpu.cycles() - start)));
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_plp_clears_break_and_sets_unused() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![
+            0x38, // SEC
+            0xf8, // SED
+            0x08, // PHP
+            0x68, // PLA
+            0x29, 0xdf, // AND #$DF (未使用ビットを落とす)
+            0x09, 0x10, // ORA #$10 (Bフラグを立てる)
+            0x48, // PHA
+            0x28, // PLP
+            0x00,
+        ]);
+
+        assert_eq!(cpu.status & BREAK_FLAG, 0);
+        assert_eq!(cpu.status & UNUSED_FLAG, UNUSED_FLAG);
+        assert_eq!(cpu.status, 0b0010_1101);
+    }
+
+    #[test]
+    fn test_rti_restores_pc_and_status() {
+        let mut program = vec![0x40]; // RTI
+        program.resize(0x10, 0x00);
+        program.extend([0xa2, 0x42, 0x00]); // $0610: LDX #$42
+        let cpu = CpuTestHarness::new()
+            // PCH、PCL、Bフラグあり・未使用ビットなしのP
+            .push_stack(&[0x06, 0x10, 0x1d])
+            .run(&program);
+
+        assert_eq!(cpu.register_x, 0x42);
+        assert_eq!(cpu.status, 0b0010_1101);
+    }
+
+    #[test]
+    fn test_nmi_pushes_status_without_break() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.status = 0b1101_0001;
+        cpu.interrupt(MNI);
+
+        let pushed = cpu.mem_read(0x0100 + cpu.stack_pointer.wrapping_add(1) as u16);
+        assert_eq!(pushed, 0b1110_0001);
+        assert_eq!(cpu.status & 0b0000_0100, 0b0000_0100);
+    }
+
+    // 64KBすべてがRAMのバス。PPUもカートリッジもない
+    struct FlatRam {
+        ram: Vec<u8>,
+        cycles: usize,
+        // Someなら読んだアドレスを順に覚えておく
+        reads: Option<Vec<u16>>,
+    }
+
+    impl FlatRam {
+        fn new() -> Self {
+            FlatRam { ram: vec![0; 0x10000], cycles: 0, reads: None }
+        }
+
+        fn with_read_log() -> Self {
+            FlatRam { reads: Some(Vec::new()), ..FlatRam::new() }
+        }
+    }
+
+    impl Mem for FlatRam {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            if let Some(reads) = &mut self.reads {
+                reads.push(addr);
+            }
+            self.ram[addr as usize]
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.ram[addr as usize] = data;
+        }
+    }
+
+    impl SystemBus for FlatRam {
+        fn tick(&mut self, cycles: u8) {
+            self.cycles += cycles as usize;
+        }
+
+        fn cycles(&self) -> usize {
+            self.cycles
+        }
+
+        fn poll_nmi_status(&mut self) -> Option<u8> {
+            None
+        }
+
+        fn peek(&mut self, addr: u16) -> u8 {
+            self.ram[addr as usize]
+        }
+    }
+
+    #[test]
+    fn test_flat_ram_bus() {
+        let mut cpu = CPU::new(FlatRam::new());
+        cpu.load_at(&[0xa9, 0x05, 0x8d, 0x00, 0xc0, 0xee, 0x00, 0xc0, 0x00], 0x8000); // LDA #$05 / STA $C000 / INC $C000
+        cpu.set_reset_vector(0x8000);
+        cpu.reset();
+
+        assert_eq!(cpu.run(), RunStop::Break);
+        assert_eq!(cpu.bus().ram[0xc000], 0x06);
+        // BRKは実行せずに止まる
+        assert_eq!(cpu.cycles(), 2 + 4 + 6);
+    }
+
+    // LDA abs,Xを1命令実行して、$2000-$2FFFへの読み出しを返す
+    fn ppu_reads_of_lda_abs_x(base: u16, x: u8, dummy_reads: bool) -> Vec<u16> {
+        let mut cpu = CPU::new(FlatRam::with_read_log());
+        cpu.set_dummy_reads(dummy_reads);
+        cpu.load_at(&[0xbd, base as u8, (base >> 8) as u8], 0x8000);
+        cpu.program_counter = 0x8000;
+        cpu.register_x = x;
+        cpu.step();
+        cpu.bus().reads.iter().flatten().copied().filter(|addr| (0x2000..0x3000).contains(addr)).collect()
+    }
+
+    #[test]
+    fn test_dummy_read_on_page_cross() {
+        // $20F2+$10 = $2102 (= $2002のミラー)。直す前の$2002も読む
+        assert_eq!(ppu_reads_of_lda_abs_x(0x20f2, 0x10, true), vec![0x2002, 0x2102]);
+        // ページをまたがなければ1回だけ
+        assert_eq!(ppu_reads_of_lda_abs_x(0x2002, 0x00, true), vec![0x2002]);
+        assert_eq!(ppu_reads_of_lda_abs_x(0x20f2, 0x10, false), vec![0x2102]);
+    }
+
+    #[test]
+    fn test_power_on_registers() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        assert_eq!((cpu.register_a, cpu.register_x, cpu.register_y), (0, 0, 0));
+        assert_eq!(cpu.stack_pointer, 0xfd);
+        assert_eq!(cpu.status, 0x24);
+
+        // PHPで見える値が資料の$34
+        cpu.load_at(&[0x08, 0x00], 0x0600);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        assert_eq!(cpu.mem_read(0x01fd), 0x34);
+    }
+
+    #[test]
+    fn test_request_nmi() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_at(&[0xea, 0xea], 0x0700); // NOP NOP
+        cpu.load_at(&[0x00, 0x07], 0xfffa);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        cpu.status = 0b1000_0001;
+
+        cpu.bus_mut().request_nmi();
+        cpu.step();
+        // NMIで$0700に飛んでから1命令実行している
+        assert_eq!(cpu.program_counter, 0x0701);
+        assert_eq!(cpu.stack_pointer, 0xfa);
+        assert_eq!(cpu.mem_read(0x01fd), 0x06);
+        assert_eq!(cpu.mem_read(0x01fc), 0x00);
+        assert_eq!(cpu.mem_read(0x01fb), 0b1010_0001);
+
+        // 1回だけ
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0702);
+        assert_eq!(cpu.stack_pointer, 0xfa);
+    }
+
+    #[test]
+    fn test_peek_stack() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        // LDA #$11 / PHA / LDA #$22 / PHA / JSR $0610
+        cpu.load_at(&[0xa9, 0x11, 0x48, 0xa9, 0x22, 0x48, 0x20, 0x10, 0x06], 0x0600);
+        cpu.load_at(&[0x00], 0x0610);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+        for _ in 0..5 {
+            cpu.step();
+        }
+
+        assert_eq!(cpu.stack_pointer, 0xf9);
+        // JSRで積んだ戻りアドレス-1 ($0608) が一番上、その下にPHAの値
+        assert_eq!(cpu.peek_stack(0), 0x08);
+        assert_eq!(cpu.peek_stack(1), 0x06);
+        assert_eq!(cpu.peek_stack(2), 0x22);
+        assert_eq!(cpu.peek_stack(3), 0x11);
+        // resetのSP($FD)より上の2バイトも含まれる
+        assert_eq!(cpu.stack_contents().len(), 6);
+        assert_eq!(cpu.stack_contents()[..4], [0x08, 0x06, 0x22, 0x11]);
+
+        cpu.poke_stack(2, 0x33);
+        assert_eq!(cpu.mem_read(0x01fc), 0x33);
+    }
+
+    #[test]
+    fn test_shx_shy_ahx_without_page_cross() {
+        // SHX $0210,Y : X & ($02+1)
+        let mut cpu = CpuTestHarness::new().register_x(0xff).register_y(0x05).run(&[0x9e, 0x10, 0x02, 0x00]);
+        assert_eq!(cpu.mem_read(0x0215), 0x03);
+        // SHY $0410,X : Y & ($04+1)
+        let mut cpu = CpuTestHarness::new().register_x(0x05).register_y(0xff).run(&[0x9c, 0x10, 0x04, 0x00]);
+        assert_eq!(cpu.mem_read(0x0415), 0x05);
+        // AHX $0310,Y : A & X & ($03+1)
+        let mut cpu = CpuTestHarness::new().register_a(0xfe).register_x(0x0f).register_y(0x05).run(&[0x9f, 0x10, 0x03, 0x00]);
+        assert_eq!(cpu.mem_read(0x0315), 0x04);
+        // AHX ($20),Y
+        let mut cpu = CpuTestHarness::new()
+            .register_a(0xff)
+            .register_x(0xff)
+            .register_y(0x05)
+            .memory(0x20, &[0x10, 0x06])
+            .run(&[0x93, 0x20, 0x00]);
+        assert_eq!(cpu.mem_read(0x0615), 0x07);
+    }
+
+    #[test]
+    fn test_shx_shy_ahx_page_cross_corrupts_address() {
+        // SHX $02F0,Y (X=$1F) : 値は$1F & $03 = $03。上位バイトが値に化けても$03なので$0310
+        let mut cpu = CpuTestHarness::new().register_x(0x1f).register_y(0x20).run(&[0x9e, 0xf0, 0x02, 0x00]);
+        assert_eq!(cpu.mem_read(0x0310), 0x03);
+
+        // SHX $04F0,Y (X=$02) : 値は$02 & $05 = $00なので$0010に書かれる
+        let mut cpu = CpuTestHarness::new()
+            .register_x(0x02)
+            .register_y(0x20)
+            .memory(0x0510, &[0xaa])
+            .memory(0x0010, &[0xaa])
+            .run(&[0x9e, 0xf0, 0x04, 0x00]);
+        assert_eq!(cpu.mem_read(0x0510), 0xaa);
+        assert_eq!(cpu.mem_read(0x0010), 0x00);
+
+        // SHY $04F0,X (Y=$03) : 値は$03 & $05 = $01なので$0110に書かれる
+        let mut cpu = CpuTestHarness::new()
+            .register_x(0x20)
+            .register_y(0x03)
+            .memory(0x0510, &[0xaa])
+            .run(&[0x9c, 0xf0, 0x04, 0x00]);
+        assert_eq!(cpu.mem_read(0x0510), 0xaa);
+        assert_eq!(cpu.mem_read(0x0110), 0x01);
+
+        // AHX $04F0,Y (A&X=$07) : 値は$07 & $05 = $05なので$0510のまま
+        let mut cpu = CpuTestHarness::new()
+            .register_a(0x07)
+            .register_x(0xff)
+            .register_y(0x20)
+            .run(&[0x9f, 0xf0, 0x04, 0x00]);
+        assert_eq!(cpu.mem_read(0x0510), 0x05);
+    }
+
+    #[test]
+    fn test_set_pc_after_reset() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        // LDA #$42 / TAX / BRK
+        cpu.load_at(&[0xa9, 0x42, 0xaa, 0x00], 0xc000);
+        cpu.reset();
+        cpu.set_pc(0xc000);
+        assert_eq!(cpu.program_counter, 0xc000);
+
+        assert_eq!(cpu.run(), RunStop::Break);
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 0x42);
+        assert_eq!(cpu.program_counter, 0xc004);
+    }
+
+    #[test]
+    fn test_kil_jams_when_enabled() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        // INX / KIL / INX / BRK
+        let program = vec![0xe8, 0x02, 0xe8, 0x00];
+
+        // 既定では何もしない命令
+        assert_eq!(cpu.load_and_run(program.clone()), RunStop::Break);
+        assert_eq!(cpu.register_x, 2);
+
+        cpu.set_jam_on_kil(true);
+        assert_eq!(cpu.load_and_run(program), RunStop::Jammed { opcode: 0x02, pc: 0x0601 });
+        assert_eq!(cpu.register_x, 1);
+        // 止まったまま
+        assert_eq!(cpu.run(), RunStop::Jammed { opcode: 0x02, pc: 0x0601 });
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.set_instruction_budget(Some(100));
+        cpu.set_opcode_histogram_enabled(true);
+
+        // JMP $0600 で無限ループ
+        assert_eq!(cpu.load_and_run(vec![0x4c, 0x00, 0x06]), RunStop::BudgetExhausted);
+        assert_eq!(cpu.opcode_histogram()[0x4c], 100);
+
+        // 上限は呼び出しごと
+        assert_eq!(cpu.run(), RunStop::BudgetExhausted);
+        assert_eq!(cpu.opcode_histogram()[0x4c], 200);
+
+        cpu.set_instruction_budget(None);
+        assert_eq!(cpu.load_and_run(vec![0xe8, 0x00]), RunStop::Break);
+    }
+
+    #[test]
+    fn test_break_on_opcode() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_at(&[0xc8, 0x60], 0x0610); // INY / RTS
+        cpu.break_on_opcode(0x20);
+
+        // LDX #$01 / INX / JSR $0610 / INX / BRK
+        let program = vec![0xa2, 0x01, 0xe8, 0x20, 0x10, 0x06, 0xe8, 0x00];
+        assert_eq!(cpu.load_and_run(program), RunStop::Breakpoint { opcode: 0x20, pc: 0x0603 });
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 2);
+        assert_eq!(cpu.register_y, 0);
+
+        // 止まったところから再開すると、そのJSRは実行される
+        assert_eq!(cpu.run(), RunStop::Break);
+        assert_eq!(cpu.register_x, 3);
+        assert_eq!(cpu.register_y, 1);
+    }
+
+    #[test]
+    fn test_break_on_instruction_kind() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.break_on_instruction_kind(InstructionKind::Store);
+
+        // LDA #$05 / TAX / STX $10 / STA $11 / BRK
+        let program = vec![0xa9, 0x05, 0xaa, 0x86, 0x10, 0x85, 0x11, 0x00];
+        assert_eq!(cpu.load_and_run(program), RunStop::Breakpoint { opcode: 0x86, pc: 0x0603 });
+        assert_eq!(cpu.run(), RunStop::Breakpoint { opcode: 0x85, pc: 0x0605 });
+
+        cpu.clear_opcode_breakpoints();
+        assert_eq!(cpu.run(), RunStop::Break);
+        assert_eq!(cpu.mem_read(0x11), 0x05);
+    }
+
+    #[test]
+    fn test_break_on_first_instruction() {
+        let bus = Bus::new(test::test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.break_on_opcode(0xe8);
+
+        // INX / CPX #$02 / BNE $0600 / BRK
+        let program = vec![0xe8, 0xe0, 0x02, 0xd0, 0xfb, 0x00];
+        // 実行を始めたところの命令でも止まる
+        assert_eq!(cpu.load_and_run(program), RunStop::Breakpoint { opcode: 0xe8, pc: 0x0600 });
+        assert_eq!(cpu.register_x, 0);
+
+        // 再開すると止まったアドレスだけ読み飛ばし、ループで戻ってきたらまた止まる
+        assert_eq!(cpu.run(), RunStop::Breakpoint { opcode: 0xe8, pc: 0x0600 });
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.run(), RunStop::Break);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_rmw_dummy_write() {
+        let mapper = Rc::new(RefCell::new(TestMapper { prg: 0x41, ..TestMapper::new() }));
+        let bus = Bus::with_mapper(mapper.clone(), PowerOnState::default(), |_ppu: &NesPPU, _joypad: &mut Joypad| {});
+        let mut cpu = CPU::new(bus);
+
+        // INC $8000
+        cpu.load_and_run(vec![0xee, 0x00, 0x80, 0x00]);
+        assert_eq!(mapper.borrow().prg_writes, vec![(0x8000, 0x41), (0x8000, 0x42)]);
+
+        // ROL $9000 (C=0)
+        *mapper.borrow_mut() = TestMapper { prg: 0x41, ..TestMapper::new() };
+        cpu.load_and_run(vec![0x18, 0x2e, 0x00, 0x90, 0x00]);
+        assert_eq!(mapper.borrow().prg_writes, vec![(0x9000, 0x41), (0x9000, 0x82)]);
+    }
 }
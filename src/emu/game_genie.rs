@@ -0,0 +1,101 @@
+// Game Genieのコード。PRG-ROMのaddrを読んだときにvalueに差し替える
+// 8文字のコードは、元の値がcompareと同じときだけ差し替える (バンク切り替えのあるゲーム用)
+// https://www.nesdev.org/nesgg.txt
+const LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameGenie {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl GameGenie {
+    pub fn decode(code: &str) -> Result<GameGenie, String> {
+        let n = code
+            .bytes()
+            .map(|c| {
+                LETTERS
+                    .iter()
+                    .position(|l| *l == c.to_ascii_uppercase())
+                    .map(|i| i as u16)
+                    .ok_or_else(|| format!("invalid Game Genie letter {:?} in {}", c as char, code))
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!("Game Genie code must be 6 or 8 letters: {}", code));
+        }
+
+        // 各文字の4bitがばらばらに並べ替えられている
+        let addr = 0x8000
+            | (n[3] & 7) << 12
+            | (n[5] & 7) << 8
+            | (n[4] & 8) << 8
+            | (n[2] & 7) << 4
+            | (n[1] & 8) << 4
+            | (n[4] & 7)
+            | (n[3] & 8);
+        let value = (n[0] & 7) | (n[0] & 8) << 4 | (n[1] & 7) << 4;
+        let (value, compare) = if n.len() == 6 {
+            (value | (n[5] & 8), None)
+        } else {
+            let compare = (n[7] & 7) << 4 | (n[6] & 8) << 4 | (n[6] & 7) | (n[5] & 8);
+            (value | (n[7] & 8), Some(compare as u8))
+        };
+        Ok(GameGenie { addr, value: value as u8, compare })
+    }
+
+    // ROMから読んだdataに対してコードを当てた値
+    pub fn patch(&self, addr: u16, data: u8) -> u8 {
+        match self.compare {
+            _ if addr != self.addr => data,
+            Some(compare) if compare != data => data,
+            _ => self.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_6_letters() {
+        // スーパーマリオブラザーズの無限1UP
+        assert_eq!(
+            GameGenie::decode("SXIOPO"),
+            Ok(GameGenie { addr: 0x91d9, value: 0xad, compare: None })
+        );
+        assert_eq!(
+            GameGenie::decode("gossip"),
+            Ok(GameGenie { addr: 0xd1dd, value: 0x14, compare: None })
+        );
+    }
+
+    #[test]
+    fn test_decode_8_letters() {
+        assert_eq!(
+            GameGenie::decode("AEKPIPZA"),
+            Ok(GameGenie { addr: 0x91c5, value: 0x00, compare: Some(0x02) })
+        );
+        // nesgg.txtに載っている例 (アドレス$94A7、値$02、比較値$03)
+        assert_eq!(
+            GameGenie::decode("ZEXPYGLA"),
+            Ok(GameGenie { addr: 0x94a7, value: 0x02, compare: Some(0x03) })
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(GameGenie::decode("SXIOP").is_err());
+        assert!(GameGenie::decode("SXIOPB").is_err());
+    }
+
+    #[test]
+    fn test_patch_honors_compare() {
+        let code = GameGenie::decode("AEKPIPZA").unwrap();
+        assert_eq!(code.patch(0x91c5, 0x02), 0x00);
+        assert_eq!(code.patch(0x91c5, 0x20), 0x20);
+        assert_eq!(code.patch(0x91c6, 0x02), 0x02);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::emu::bus::Bus;
+use crate::emu::cartridge::test::test_rom;
+use crate::emu::cpu::{Mem, CPU};
+use crate::joypad::Joypad;
+use crate::ppu_emu::ppu::NesPPU;
+
+// CPUのテスト用。レジスタやメモリを並べて書いてから、$0600に置いたプログラムをBRKまで実行する
+//
+//     let cpu = CpuTestHarness::new().register_a(0x10).memory(0x20, &[0x05]).run(&[0x65, 0x20, 0x00]);
+//     assert_eq!(cpu.register_a, 0x15);
+pub struct CpuTestHarness {
+    register_a: u8,
+    register_x: u8,
+    register_y: u8,
+    status: Option<u8>,
+    stack_pointer: Option<u8>,
+    stack: Vec<u8>,
+    memory: Vec<(u16, Vec<u8>)>,
+}
+
+impl CpuTestHarness {
+    pub fn new() -> Self {
+        CpuTestHarness {
+            register_a: 0,
+            register_x: 0,
+            register_y: 0,
+            status: None,
+            stack_pointer: None,
+            stack: Vec::new(),
+            memory: Vec::new(),
+        }
+    }
+
+    pub fn register_a(mut self, value: u8) -> Self {
+        self.register_a = value;
+        self
+    }
+
+    pub fn register_x(mut self, value: u8) -> Self {
+        self.register_x = value;
+        self
+    }
+
+    pub fn register_y(mut self, value: u8) -> Self {
+        self.register_y = value;
+        self
+    }
+
+    // 指定しなければリセット直後の値 ($24)
+    pub fn status(mut self, status: u8) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    // statusの一部のビットだけを立てる/落とす
+    pub fn flag(mut self, flag: u8, on: bool) -> Self {
+        let status = self.status.unwrap_or(0b0010_0100);
+        self.status = Some(if on { status | flag } else { status & !flag });
+        self
+    }
+
+    // 指定しなければ$FD。積んだ分はここから下に伸びる
+    pub fn stack_pointer(mut self, value: u8) -> Self {
+        self.stack_pointer = Some(value);
+        self
+    }
+
+    // PHAと同じ順に積む。最後の値が最初にPLAで取り出される
+    pub fn push_stack(mut self, bytes: &[u8]) -> Self {
+        self.stack.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn memory(mut self, addr: u16, bytes: &[u8]) -> Self {
+        self.memory.push((addr, bytes.to_vec()));
+        self
+    }
+
+    pub fn run(self, program: &[u8]) -> CPU<'static> {
+        let bus = Bus::new(test_rom(), |_ppu: &NesPPU, _joypad: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load_at(program, 0x0600);
+        cpu.reset();
+
+        for (addr, bytes) in &self.memory {
+            for (i, byte) in bytes.iter().enumerate() {
+                cpu.mem_write(addr.wrapping_add(i as u16), *byte);
+            }
+        }
+        if let Some(stack_pointer) = self.stack_pointer {
+            cpu.stack_pointer = stack_pointer;
+        }
+        for byte in &self.stack {
+            cpu.mem_write(0x0100 + cpu.stack_pointer as u16, *byte);
+            cpu.stack_pointer = cpu.stack_pointer.wrapping_sub(1);
+        }
+        cpu.register_a = self.register_a;
+        cpu.register_x = self.register_x;
+        cpu.register_y = self.register_y;
+        if let Some(status) = self.status {
+            cpu.status = status;
+        }
+        cpu.set_pc(0x0600);
+        cpu.run();
+        cpu
+    }
+}
+
+impl Default for CpuTestHarness {
+    fn default() -> Self {
+        CpuTestHarness::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_presets_are_applied() {
+        let cpu = CpuTestHarness::new()
+            .register_a(0x10)
+            .register_x(0x01)
+            .flag(0b0000_0001, true)
+            .memory(0x21, &[0x05])
+            .run(&[0x75, 0x20, 0x00]); // ADC $20,X
+
+        assert_eq!(cpu.register_a, 0x16);
+        assert_eq!(cpu.register_x, 0x01);
+    }
+
+    #[test]
+    fn test_push_stack_order() {
+        let cpu = CpuTestHarness::new()
+            .push_stack(&[0x11, 0x22])
+            .run(&[0x68, 0xaa, 0x68, 0x00]); // PLA / TAX / PLA
+
+        assert_eq!(cpu.register_x, 0x22);
+        assert_eq!(cpu.register_a, 0x11);
+        assert_eq!(cpu.stack_pointer, 0xfd);
+    }
+}
@@ -1,6 +1,7 @@
 #[derive(PartialEq, Eq)]
 pub enum InterruptType {
     MNI,
+    IRQ,
 }
 
 #[derive(PartialEq, Eq)]
@@ -17,3 +18,10 @@ pub const MNI: Interrupt = Interrupt {
     b_flag_mask: 0b00100000,
     cpu_cycles: 2,
 };
+
+pub const IRQ: Interrupt = Interrupt {
+    itype: InterruptType::IRQ,
+    vector_addr: 0xfffe,
+    b_flag_mask: 0b00100000,
+    cpu_cycles: 2,
+};
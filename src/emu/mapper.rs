@@ -0,0 +1,148 @@
+pub mod axrom;
+pub mod mmc3;
+pub mod nrom;
+
+use crate::emu::cartridge::{Mirroring, Rom, RomData, RomError};
+use crate::savestate::{StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// カートリッジ側の回路。CPUからはPRG($8000-$FFFF)、PPUからはCHR($0000-$1FFF)として見える
+pub trait Mapper {
+    fn id(&self) -> u8;
+
+    fn read_prg(&self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, data: u8);
+    // レジスタを通さずに、今addrに見えているPRG-ROMを書き換える (テストやデバッグ用)
+    fn poke_prg(&mut self, _addr: u16, _data: u8) {}
+    // 今addrに見えているのがPRG-ROMの何バイト目か。分からなければNone (デバッガ用)
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, data: u8);
+    // addrがCHR-RAMならtrue。ROMとRAMが混在するカートリッジもあるのでアドレスごとに答える
+    fn chr_writable(&self, _addr: u16) -> bool {
+        false
+    }
+
+    fn mirroring(&self) -> Mirroring;
+
+    // PPUのアドレスバスに出たアドレス。A12の変化を見るマッパー(MMC3)が使う
+    fn notify_ppu_addr(&mut self, _addr: u16) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // save_stateで書く中身の形式の版。並びを変えたら上げる
+    fn state_version(&self) -> u8 {
+        1
+    }
+
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+// マッパー番号と版を先頭に付けて書く。別のマッパーや古い形式のステートは読み込む前に弾く
+pub fn save_mapper_state(mapper: &dyn Mapper, w: &mut StateWriter) {
+    w.write_u8(mapper.id());
+    w.write_u8(mapper.state_version());
+    mapper.save_state(w);
+}
+
+pub fn load_mapper_state(mapper: &mut dyn Mapper, r: &mut StateReader) -> Result<(), String> {
+    let id = r.read_u8()?;
+    if id != mapper.id() {
+        return Err(format!("save state is for mapper {}, but the cartridge uses mapper {}", id, mapper.id()));
+    }
+    let version = r.read_u8()?;
+    if version != mapper.state_version() {
+        return Err(format!(
+            "unsupported state version {} for mapper {} (expected {})",
+            version, id, mapper.state_version()
+        ));
+    }
+    mapper.load_state(r)
+}
+
+// BusとPPUの両方から触るので共有する
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+// 対応していないマッパーを別のマッパーとして動かすと画面が崩れるだけなので、エラーにする
+pub fn from_rom(rom: Rom) -> Result<SharedMapper, RomError> {
+    let mapper: SharedMapper = match rom.mapper {
+        0 => Rc::new(RefCell::new(nrom::Nrom::new(rom))),
+        4 => Rc::new(RefCell::new(mmc3::Mmc3::new(rom))),
+        7 => Rc::new(RefCell::new(axrom::Axrom::new(rom))),
+        id => return Err(RomError::UnsupportedMapper(id)),
+    };
+    Ok(mapper)
+}
+
+// CHR-ROMがなければ8KBのCHR-RAMを積んでいる
+fn chr_or_ram(chr_rom: RomData) -> (RomData, bool) {
+    if chr_rom.is_empty() {
+        (RomData::Owned(vec![0; 0x2000]), true)
+    } else {
+        (chr_rom, false)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // テスト用のマッパー。$8000-$FFFFに書いた値がそのまま読め、書き込みは順に記録する
+    // CHRはchr_ram_startより後ろだけ書き込める
+    pub struct TestMapper {
+        pub prg: u8,
+        pub prg_writes: Vec<(u16, u8)>,
+        pub chr: Vec<u8>,
+        pub chr_ram_start: u16,
+    }
+
+    impl TestMapper {
+        pub fn new() -> Self {
+            TestMapper { prg: 0, prg_writes: Vec::new(), chr: vec![0; 0x2000], chr_ram_start: 0x2000 }
+        }
+    }
+
+    impl Default for TestMapper {
+        fn default() -> Self {
+            TestMapper::new()
+        }
+    }
+
+    impl Mapper for TestMapper {
+        fn id(&self) -> u8 {
+            255
+        }
+        fn read_prg(&self, _addr: u16) -> u8 {
+            self.prg
+        }
+        fn write_prg(&mut self, addr: u16, data: u8) {
+            self.prg = data;
+            self.prg_writes.push((addr, data));
+        }
+        fn read_chr(&self, addr: u16) -> u8 {
+            self.chr[addr as usize]
+        }
+        fn write_chr(&mut self, addr: u16, data: u8) {
+            if self.chr_writable(addr) {
+                self.chr[addr as usize] = data;
+            }
+        }
+        fn chr_writable(&self, addr: u16) -> bool {
+            addr >= self.chr_ram_start
+        }
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::HORIZONTAL
+        }
+        fn save_state(&self, _w: &mut StateWriter) {}
+        fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::emu::cartridge::{Mirroring, Rom, RomData};
+use crate::emu::mapper::{chr_or_ram, Mapper};
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+// マッパー7 (AxROM)。PRGは32KB単位で切り替え、ネームテーブルは1画面
+// https://www.nesdev.org/wiki/AxROM
+pub struct Axrom {
+    prg_rom: RomData,
+    chr: RomData,
+    chr_is_ram: bool,
+    // bit0-2: PRGバンク、bit4: 1画面のネームテーブル(0ならA、1ならB)
+    bank: u8,
+}
+
+impl Axrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_or_ram(rom.chr_rom);
+        Axrom {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            bank: 0,
+        }
+    }
+
+    fn prg_index(&self, addr: u16) -> usize {
+        let banks = (self.prg_rom.len() / PRG_BANK_SIZE).max(1);
+        let bank = (self.bank & 0b111) as usize % banks;
+        (bank * PRG_BANK_SIZE + (addr - 0x8000) as usize) % self.prg_rom.len()
+    }
+}
+
+impl Mapper for Axrom {
+    fn id(&self) -> u8 {
+        7
+    }
+
+    fn read_prg(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_index(addr)]
+    }
+
+    fn write_prg(&mut self, _addr: u16, data: u8) {
+        self.bank = data;
+    }
+
+    fn poke_prg(&mut self, addr: u16, data: u8) {
+        let index = self.prg_index(addr);
+        self.prg_rom[index] = data;
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.prg_index(addr))
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[addr as usize & 0x1fff]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_writable(addr) {
+            self.chr[addr as usize & 0x1fff] = data;
+        }
+    }
+
+    fn chr_writable(&self, _addr: u16) -> bool {
+        self.chr_is_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.bank & 0b1_0000 == 0 {
+            Mirroring::SINGLE_SCREEN_A
+        } else {
+            Mirroring::SINGLE_SCREEN_B
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank);
+        if self.chr_is_ram {
+            w.write_bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.read_u8()?;
+        if self.chr_is_ram {
+            r.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emu::cartridge::RomBuilder;
+    use crate::ppu_emu::ppu::NesPPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_prg_bank_and_single_screen() {
+        // 32KBバンク4個。各バンクの中身はバンク番号
+        let prg: Vec<u8> = (0..4).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        let axrom = Rc::new(RefCell::new(Axrom::new(RomBuilder::new().mapper(7).prg(&prg).chr(&[]).build())));
+        let ppu = NesPPU::with_mapper(axrom.clone());
+
+        assert_eq!(axrom.borrow().read_prg(0x8000), 0);
+        assert_eq!(ppu.mirroring(), Mirroring::SINGLE_SCREEN_A);
+        assert_eq!(ppu.mirror_vram_addr(0x2c05), 0x005);
+
+        axrom.borrow_mut().write_prg(0xc000, 0b0001_0010);
+        assert_eq!(axrom.borrow().read_prg(0x8000), 2);
+        assert_eq!(axrom.borrow().read_prg(0xffff), 2);
+        assert_eq!(ppu.mirroring(), Mirroring::SINGLE_SCREEN_B);
+        for addr in [0x2005, 0x2405, 0x2805, 0x2c05] {
+            assert_eq!(ppu.mirror_vram_addr(addr), 0x405);
+        }
+    }
+}
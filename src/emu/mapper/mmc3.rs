@@ -0,0 +1,338 @@
+use crate::emu::cartridge::{Mirroring, Rom, RomData};
+use crate::emu::mapper::{chr_or_ram, Mapper};
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// マッパー4 (MMC3)
+// https://www.nesdev.org/wiki/MMC3
+pub struct Mmc3 {
+    prg_rom: RomData,
+    chr: RomData,
+    chr_is_ram: bool,
+    four_screen: bool,
+
+    // $8000: 次に$8001で書き換えるレジスタ(R0-R7)とバンクの配置
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    last_a12: bool,
+}
+
+impl Mmc3 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_or_ram(rom.chr_rom);
+        Mmc3 {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            four_screen: rom.screen_mirroring == Mirroring::FOUR_SCREEN,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: rom.screen_mirroring,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            last_a12: false,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    // $8000-$FFFFの8KB単位のスロットに入るバンク番号
+    fn prg_bank(&self, slot: usize) -> usize {
+        let second_last = self.prg_bank_count().saturating_sub(2);
+        let prg_mode = self.bank_select & 0b0100_0000 != 0;
+        let bank = match (slot, prg_mode) {
+            (0, false) | (2, true) => self.registers[6] as usize,
+            (0, true) | (2, false) => second_last,
+            (1, _) => self.registers[7] as usize,
+            _ => self.prg_bank_count().saturating_sub(1),
+        };
+        bank % self.prg_bank_count()
+    }
+
+    fn prg_index(&self, addr: u16) -> usize {
+        let addr = (addr - 0x8000) as usize;
+        self.prg_bank(addr / PRG_BANK_SIZE) * PRG_BANK_SIZE + addr % PRG_BANK_SIZE
+    }
+
+    // $0000-$1FFFの1KB単位のスロットに入るバンク番号
+    fn chr_bank(&self, slot: usize) -> usize {
+        // A12反転: 2KBバンク2つと1KBバンク4つの位置を入れ替える
+        let slot = if self.bank_select & 0b1000_0000 != 0 { slot ^ 4 } else { slot };
+        let bank = match slot {
+            0 => self.registers[0] & 0xfe,
+            1 => self.registers[0] | 1,
+            2 => self.registers[1] & 0xfe,
+            3 => self.registers[1] | 1,
+            _ => self.registers[slot - 2],
+        } as usize;
+        bank % (self.chr.len() / CHR_BANK_SIZE)
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x1fff;
+        self.chr_bank(addr / CHR_BANK_SIZE) * CHR_BANK_SIZE + addr % CHR_BANK_SIZE
+    }
+
+    // PPUのA12が0から1になるたび(普通は1スキャンラインに1回)呼ばれる
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn id(&self) -> u8 {
+        4
+    }
+
+    fn read_prg(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_index(addr)]
+    }
+
+    fn poke_prg(&mut self, addr: u16, data: u8) {
+        let index = self.prg_index(addr);
+        self.prg_rom[index] = data;
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000..=0x9fff, true) => self.bank_select = data,
+            (0x8000..=0x9fff, false) => self.registers[(self.bank_select & 0b111) as usize] = data,
+            (0xa000..=0xbfff, true) if !self.four_screen => {
+                self.mirroring = if data & 1 == 0 { Mirroring::VERTICAL } else { Mirroring::HORIZONTAL };
+            }
+            // PRG-RAMの書き込み保護。PRG-RAMは常に使えるようにしているので無視する
+            (0xa000..=0xbfff, false) => {}
+            (0xc000..=0xdfff, true) => self.irq_latch = data,
+            (0xc000..=0xdfff, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xe000..=0xffff, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xe000..=0xffff, false) => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.prg_index(addr))
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_writable(addr) {
+            let index = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn chr_writable(&self, _addr: u16) -> bool {
+        self.chr_is_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn notify_ppu_addr(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.last_a12 {
+            self.clock_irq_counter();
+        }
+        self.last_a12 = a12;
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.bank_select);
+        w.write_bytes(&self.registers);
+        w.write_bool(self.mirroring == Mirroring::VERTICAL);
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.last_a12);
+        if self.chr_is_ram {
+            w.write_bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank_select = r.read_u8()?;
+        r.read_bytes(&mut self.registers)?;
+        let vertical = r.read_bool()?;
+        if !self.four_screen {
+            self.mirroring = if vertical { Mirroring::VERTICAL } else { Mirroring::HORIZONTAL };
+        }
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.last_a12 = r.read_bool()?;
+        if self.chr_is_ram {
+            r.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emu::cartridge::RomBuilder;
+    use crate::emu::mapper::{axrom::Axrom, load_mapper_state, save_mapper_state};
+
+    // PRGは8KBバンク8個、CHRは1KBバンク16個。各バンクの中身はバンク番号
+    fn test_mmc3() -> Mmc3 {
+        let prg: Vec<u8> = (0..8).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        let chr: Vec<u8> = (0..16).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect();
+        Mmc3::new(RomBuilder::new().mapper(4).prg(&prg).chr(&chr).build())
+    }
+
+    #[test]
+    fn test_prg_bank_select() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0x8000, 6);
+        mmc3.write_prg(0x8001, 3);
+        mmc3.write_prg(0x8000, 7);
+        mmc3.write_prg(0x8001, 5);
+
+        assert_eq!(mmc3.read_prg(0x8000), 3);
+        assert_eq!(mmc3.read_prg(0xa000), 5);
+        assert_eq!(mmc3.read_prg(0xc000), 6);
+        assert_eq!(mmc3.read_prg(0xe000), 7);
+
+        // PRGモード1では$8000と$C000が入れ替わる
+        mmc3.write_prg(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.read_prg(0x8000), 6);
+        assert_eq!(mmc3.read_prg(0xc000), 3);
+        assert_eq!(mmc3.read_prg(0xffff), 7);
+    }
+
+    #[test]
+    fn test_chr_bank_select() {
+        let mut mmc3 = test_mmc3();
+        for (register, bank) in [(0, 8), (1, 10), (2, 1), (3, 2), (4, 3), (5, 4)] {
+            mmc3.write_prg(0x8000, register);
+            mmc3.write_prg(0x8001, bank);
+        }
+
+        let banks: Vec<u8> = (0..8).map(|slot| mmc3.read_chr(slot * 0x400)).collect();
+        assert_eq!(banks, vec![8, 9, 10, 11, 1, 2, 3, 4]);
+
+        mmc3.write_prg(0x8000, 0b1000_0000);
+        let banks: Vec<u8> = (0..8).map(|slot| mmc3.read_chr(slot * 0x400)).collect();
+        assert_eq!(banks, vec![1, 2, 3, 4, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0xa000, 0);
+        assert_eq!(mmc3.mirroring(), Mirroring::VERTICAL);
+        mmc3.write_prg(0xa000, 1);
+        assert_eq!(mmc3.mirroring(), Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_state_keeps_banks_and_irq() {
+        let mut mmc3 = test_mmc3();
+        for (register, bank) in [(6, 3), (7, 5), (0, 8)] {
+            mmc3.write_prg(0x8000, register);
+            mmc3.write_prg(0x8001, bank);
+        }
+        mmc3.write_prg(0x8000, 0b0100_0000);
+        mmc3.write_prg(0xc000, 7);
+        mmc3.write_prg(0xe001, 0);
+        rise_a12(&mut mmc3);
+
+        let mut w = StateWriter::new();
+        save_mapper_state(&mmc3, &mut w);
+        let state = w.into_bytes();
+        assert_eq!(state[0..2], [4, 1]);
+
+        let mut restored = test_mmc3();
+        load_mapper_state(&mut restored, &mut StateReader::new(&state)).unwrap();
+        let banks = |m: &Mmc3| [0x8000, 0xa000, 0xc000, 0xe000, 0x0000].map(|addr: u16| if addr >= 0x8000 { m.read_prg(addr) } else { m.read_chr(addr) });
+        assert_eq!(banks(&restored), banks(&mmc3));
+        assert_eq!(banks(&restored), [6, 5, 3, 7, 8]);
+        assert_eq!(restored.irq_counter, 7);
+        assert!(restored.irq_enabled);
+
+        // 別のマッパーや知らない版は読み込まない
+        let mut axrom = Axrom::new(RomBuilder::new().mapper(7).build());
+        let err = load_mapper_state(&mut axrom, &mut StateReader::new(&state)).unwrap_err();
+        assert_eq!(err, "save state is for mapper 4, but the cartridge uses mapper 7");
+        let mut newer = state.clone();
+        newer[1] = 2;
+        let err = load_mapper_state(&mut restored, &mut StateReader::new(&newer)).unwrap_err();
+        assert_eq!(err, "unsupported state version 2 for mapper 4 (expected 1)");
+    }
+
+    fn rise_a12(mmc3: &mut Mmc3) {
+        mmc3.notify_ppu_addr(0x0000);
+        mmc3.notify_ppu_addr(0x1000);
+    }
+
+    #[test]
+    fn test_irq_counter_on_a12_rises() {
+        let mut mmc3 = test_mmc3();
+        mmc3.write_prg(0xc000, 2);
+        mmc3.write_prg(0xc001, 0);
+        mmc3.write_prg(0xe001, 0);
+
+        // 1回目でラッチの値を読み込み、そこから1ずつ減る
+        rise_a12(&mut mmc3);
+        assert_eq!(mmc3.irq_counter, 2);
+        // A12が1のままなら数えない
+        mmc3.notify_ppu_addr(0x1ff0);
+        assert_eq!(mmc3.irq_counter, 2);
+        rise_a12(&mut mmc3);
+        assert_eq!(mmc3.irq_counter, 1);
+        assert!(!mmc3.irq_pending());
+        rise_a12(&mut mmc3);
+        assert_eq!(mmc3.irq_counter, 0);
+        assert!(mmc3.irq_pending());
+
+        // $E000で止めて解除する
+        mmc3.write_prg(0xe000, 0);
+        assert!(!mmc3.irq_pending());
+        rise_a12(&mut mmc3);
+        rise_a12(&mut mmc3);
+        rise_a12(&mut mmc3);
+        assert!(!mmc3.irq_pending());
+    }
+}
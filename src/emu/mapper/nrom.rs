@@ -0,0 +1,122 @@
+use crate::emu::cartridge::{Mirroring, Rom, RomData};
+use crate::emu::mapper::{chr_or_ram, Mapper};
+use crate::savestate::{StateReader, StateWriter};
+
+// マッパー0。バンク切り替えなし
+pub struct Nrom {
+    prg_rom: RomData,
+    // PRG-ROMの長さを2のべき乗に切り上げたもの-1。16KB(NROM-128)なら$C000-$FFFFに$8000-$BFFFが見える
+    prg_mask: usize,
+    chr: RomData,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        // 16KB/32KB以外は2のべき乗までミラーし、その中で足りない分は0を読む
+        let len = rom.prg_rom.len();
+        let (chr, chr_is_ram) = chr_or_ram(rom.chr_rom);
+        Nrom {
+            prg_rom: rom.prg_rom,
+            prg_mask: len.next_power_of_two().saturating_sub(1),
+            chr,
+            chr_is_ram,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+
+    fn prg_index(&self, addr: u16) -> Option<usize> {
+        let index = (addr - 0x8000) as usize & self.prg_mask;
+        if index < self.prg_rom.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn read_prg(&self, addr: u16) -> u8 {
+        self.prg_index(addr).map_or(0, |i| self.prg_rom[i])
+    }
+
+    fn write_prg(&mut self, _addr: u16, _data: u8) {}
+
+    fn poke_prg(&mut self, addr: u16, data: u8) {
+        if let Some(i) = self.prg_index(addr) {
+            self.prg_rom[i] = data;
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.prg_index(addr)
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_writable(addr) {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn chr_writable(&self, _addr: u16) -> bool {
+        self.chr_is_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.chr_is_ram {
+            w.write_bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if self.chr_is_ram {
+            r.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emu::cartridge::RomBuilder;
+
+    #[test]
+    fn test_prg_mask_from_length() {
+        let prg: Vec<u8> = (0..0x2000).map(|i| (i >> 8) as u8).collect();
+        let nrom = Nrom::new(RomBuilder::new().prg(&prg).build());
+        // 8KBは$8000-$FFFFに4回見える
+        for base in [0x8000, 0xa000, 0xc000, 0xe000] {
+            assert_eq!(nrom.read_prg(base + 0x1234), 0x12);
+        }
+
+        // 24KBは32KBに切り上げて、足りない分は0
+        let nrom = Nrom::new(RomBuilder::new().prg(&[0xea; 0x6000]).build());
+        assert_eq!(nrom.read_prg(0xdfff), 0xea);
+        assert_eq!(nrom.read_prg(0xe000), 0);
+    }
+
+    #[test]
+    fn test_chr_ram_is_writable() {
+        let mut nrom = Nrom::new(RomBuilder::new().chr(&[]).build());
+        nrom.write_chr(0x1234, 0x56);
+        assert_eq!(nrom.read_chr(0x1234), 0x56);
+
+        let mut nrom = Nrom::new(RomBuilder::new().chr(&[0x11; 0x2000]).build());
+        nrom.write_chr(0x1234, 0x56);
+        assert_eq!(nrom.read_chr(0x1234), 0x11);
+    }
+}
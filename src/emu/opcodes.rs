@@ -353,3 +353,31 @@ pub static OPECODES_MAP: Lazy<HashMap<u8, &'static OpCode>> = Lazy::new(|| {
     }
     map
 });
+
+// デバッガで命令の種類ごとに止めるための分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionKind {
+    // BCC, BEQ など条件分岐
+    Branch,
+    // メモリに書き込むだけの命令 (非公式のSAXなども含む)
+    Store,
+    // JMP, JSR
+    Jump,
+    // RTS, RTI
+    Return,
+    // 非公式命令すべて
+    Unofficial,
+}
+
+impl InstructionKind {
+    pub fn includes(&self, op: &OpCode) -> bool {
+        let mnemonic = op.mnemonic.trim_start_matches('*');
+        match self {
+            InstructionKind::Branch => matches!(mnemonic, "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BVC" | "BVS"),
+            InstructionKind::Store => matches!(mnemonic, "STA" | "STX" | "STY" | "SAX" | "AHX" | "SHX" | "SHY" | "TAS"),
+            InstructionKind::Jump => matches!(mnemonic, "JMP" | "JSR"),
+            InstructionKind::Return => matches!(mnemonic, "RTS" | "RTI"),
+            InstructionKind::Unofficial => op.mnemonic.starts_with('*'),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// PCの範囲ごとに、そこから始まった命令のサイクル数を数える
+pub struct Profiler {
+    range_size: usize,
+    cycles: Vec<u64>,
+}
+
+// 1ページ (上位バイトごと)
+pub const PAGE: usize = 0x100;
+// 8KB (MMC3などのPRGバンクの単位)
+pub const BANK_8K: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileEntry {
+    pub start: u16,
+    pub end: u16,
+    pub cycles: u64,
+}
+
+impl Profiler {
+    pub fn new(range_size: usize) -> Self {
+        assert!((1..=0x10000).contains(&range_size), "range size must be 1..=0x10000, got {}", range_size);
+        Profiler {
+            range_size,
+            cycles: vec![0; 0x10000usize.div_ceil(range_size)],
+        }
+    }
+
+    pub fn record(&mut self, pc: u16, cycles: usize) {
+        self.cycles[pc as usize / self.range_size] += cycles as u64;
+    }
+
+    // サイクル数の多い順。一度も実行していない範囲は含めない
+    pub fn report(&self) -> Vec<ProfileEntry> {
+        let mut entries: Vec<ProfileEntry> = self
+            .cycles
+            .iter()
+            .enumerate()
+            .filter(|(_, cycles)| **cycles > 0)
+            .map(|(i, cycles)| {
+                let start = i * self.range_size;
+                ProfileEntry {
+                    start: start as u16,
+                    end: (start + self.range_size - 1).min(0xffff) as u16,
+                    cycles: *cycles,
+                }
+            })
+            .collect();
+        entries.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.start.cmp(&b.start)));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut profiler = Profiler::new(BANK_8K);
+        profiler.record(0x8000, 2);
+        profiler.record(0x9fff, 3);
+        profiler.record(0xe000, 7);
+        profiler.record(0xffff, 1);
+
+        assert_eq!(
+            profiler.report(),
+            vec![
+                ProfileEntry { start: 0xe000, end: 0xffff, cycles: 8 },
+                ProfileEntry { start: 0x8000, end: 0x9fff, cycles: 5 },
+            ]
+        );
+    }
+}
@@ -3,6 +3,28 @@ use crate::emu::cpu::{CPU, Mem};
 use crate::emu::opcodes;
 use std::collections::HashMap;
 
+// 命令を実行する直前のCPUの状態
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceInfo {
+    pub pc: u16,
+    pub opcode: [u8; 3],
+    pub len: u8,
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub cycles: usize,
+}
+
+pub type TraceHook<'a> = Box<dyn FnMut(&TraceInfo) + 'a>;
+
+impl TraceInfo {
+    pub fn bytes(&self) -> &[u8] {
+        &self.opcode[..self.len as usize]
+    }
+}
+
 pub fn trace(cpu: &mut CPU) -> String {
     let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPECODES_MAP;
 
@@ -0,0 +1,423 @@
+use crate::emu::bus::Bus;
+use crate::emu::cartridge::{Rom, RomError};
+use crate::emu::cpu::{Mem, RunStop, CPU};
+use crate::joypad::Joypad;
+use crate::ppu_emu::ppu::NesPPU;
+use crate::render::frame::Frame;
+use crate::render_screen;
+use crate::savestate::{StateReader, StateWriter};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+const SAVE_STATE_MAGIC: [u8; 4] = *b"NESS";
+const SAVE_STATE_VERSION: u8 = 10;
+
+/// ROMを読み込んでフレーム単位で実行するためのエントリポイント
+///
+/// ```
+/// use nes_emu::emu::cartridge::RomBuilder;
+/// use nes_emu::emulator::Emulator;
+/// use nes_emu::joypad::Joypad;
+///
+/// // JMP $8000 で無限ループするだけのROM
+/// let mut prg = vec![0; 0x8000];
+/// prg[0..3].copy_from_slice(&[0x4c, 0x00, 0x80]);
+/// prg[0x7ffc] = 0x00;
+/// prg[0x7ffd] = 0x80;
+///
+/// let mut emulator = Emulator::new(RomBuilder::new().prg(&prg).build()).unwrap();
+/// let mut joypad = Joypad::new();
+/// let frame = emulator.step_frame(&mut joypad).unwrap();
+/// assert_eq!(frame.data.len(), 256 * 240 * 3);
+/// ```
+pub struct Emulator {
+    cpu: CPU<'static>,
+    frame: Frame,
+    stats: Stats,
+    entropy: Option<Entropy>,
+}
+
+// フレームの頭にaddrへ乱数を書き込む。古いsnakeのデモは$FEを乱数として読んでいた
+// 種と状態はセーブステートに入るので、リプレイしても同じ値が出る
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entropy {
+    addr: u16,
+    state: u64,
+}
+
+impl Entropy {
+    // splitmix64。種が0でも回る
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+// 直前のフレームの計測値と、起動してからの累計
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub frames: u64,
+    pub instructions: u64,
+    pub ppu_cycles: u64,
+    pub render_time: Duration,
+    pub total_instructions: u64,
+    pub total_ppu_cycles: u64,
+}
+
+impl Emulator {
+    pub fn new(rom: Rom) -> Result<Self, RomError> {
+        let bus = Bus::new(rom, |_ppu: &NesPPU, _joypad: &mut Joypad| {})?;
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        Ok(Emulator {
+            cpu,
+            frame: Frame::new(),
+            stats: Stats::default(),
+            entropy: None,
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    pub fn cpu(&self) -> &CPU<'static> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<'static> {
+        &mut self.cpu
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    // 256x240のRGB (1ピクセル3バイト)。WebならそのままCanvasに転送できる
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame.data
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    // 毎フレームaddrにseedから作った乱数を書き込む。Noneで止める
+    pub fn set_entropy(&mut self, entropy: Option<(u16, u64)>) {
+        self.entropy = entropy.map(|(addr, seed)| Entropy { addr, state: seed });
+    }
+
+    // 1フレーム分実行して描画する。joypadはこのフレームの間だけコントローラ1として使う
+    // BRKなどでCPUが止まったら、途中までのフレームは描かずに止まった理由を返す
+    pub fn step_frame(&mut self, joypad: &mut Joypad) -> Result<&Frame, RunStop> {
+        std::mem::swap(self.cpu.bus_mut().joypad1_mut(), joypad);
+        if let Some(entropy) = &mut self.entropy {
+            let value = entropy.next() as u8;
+            self.cpu.mem_write(entropy.addr, value);
+        }
+        let start_cycles = self.cpu.cycles();
+        let mut instructions = 0;
+        let stop = loop {
+            instructions += 1;
+            if let Some(stop) = self.cpu.step() {
+                break Some(stop);
+            }
+            if self.cpu.bus_mut().poll_frame_complete() {
+                break None;
+            }
+        };
+        std::mem::swap(self.cpu.bus_mut().joypad1_mut(), joypad);
+        if let Some(stop) = stop {
+            return Err(stop);
+        }
+
+        let ppu = self.cpu.bus().ppu();
+        let frame = &mut self.frame;
+        let render_time = timed(|| render_screen::render(ppu, frame));
+
+        let ppu_cycles = (self.cpu.cycles() - start_cycles) as u64 * 3;
+        self.stats = Stats {
+            frames: self.stats.frames + 1,
+            instructions,
+            ppu_cycles,
+            render_time,
+            total_instructions: self.stats.total_instructions + instructions,
+            total_ppu_cycles: self.stats.total_ppu_cycles + ppu_cycles,
+        };
+        Ok(&self.frame)
+    }
+
+    // 直前のフレームで鳴った音 (DEFAULT_OUTPUT_RATEのモノラル)。step_frameのたびに取り出す
+    // 取り出さなければ次のフレームの分で置き換わる
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus_mut().take_frame_samples()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_bytes(&SAVE_STATE_MAGIC);
+        w.write_u8(SAVE_STATE_VERSION);
+        self.cpu.save_state(&mut w);
+        w.write_bool(self.entropy.is_some());
+        if let Some(entropy) = &self.entropy {
+            w.write_u16(entropy.addr);
+            w.write_u64(entropy.state);
+        }
+        w.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = StateReader::new(data);
+        let mut magic = [0; 4];
+        r.read_bytes(&mut magic)?;
+        if magic != SAVE_STATE_MAGIC {
+            return Err("not a save state".to_string());
+        }
+        let version = r.read_u8()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(format!("unsupported save state version {}", version));
+        }
+        self.cpu.load_state(&mut r)?;
+        self.entropy = if r.read_bool()? {
+            Some(Entropy { addr: r.read_u16()?, state: r.read_u64()? })
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn timed<F: FnOnce()>(f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+// wasm32-unknown-unknownではInstant::nowがpanicするので計らない
+#[cfg(target_arch = "wasm32")]
+fn timed<F: FnOnce()>(f: F) -> Duration {
+    f();
+    Duration::ZERO
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emu::cartridge::RomBuilder;
+
+    // リセットでNMIを有効にして待つだけ。NMIごとに$10をインクリメントする
+    fn nmi_counter_rom() -> Rom {
+        let mut prg = vec![0; 0x8000];
+        let program = [
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000
+            0x4c, 0x05, 0x80, // JMP $8005
+            0xe6, 0x10, // INC $10
+            0x40, // RTI
+        ];
+        prg[0..program.len()].copy_from_slice(&program);
+        prg[0x7ffa..0x8000].copy_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        RomBuilder::new().prg(&prg).build()
+    }
+
+    // MMC3で16ラインごとにIRQを起こし、IRQごとに$10をインクリメントする
+    fn mmc3_irq_counter_rom() -> Rom {
+        let mut prg = vec![0; 0x8000];
+        let program = [
+            0xa9, 0x08, 0x8d, 0x00, 0x20, // LDA #$08 / STA $2000 (スプライトは$1000)
+            0xa9, 0x18, 0x8d, 0x01, 0x20, // LDA #$18 / STA $2001
+            0xa9, 0x10, 0x8d, 0x00, 0xc0, // LDA #$10 / STA $C000 (ラッチ)
+            0x8d, 0x01, 0xc0, // STA $C001 (リロード)
+            0x8d, 0x01, 0xe0, // STA $E001 (IRQ有効)
+            0x58, // CLI
+            0x4c, 0x16, 0xe0, // JMP $E016
+            0xe6, 0x10, // $E019: INC $10
+            0x8d, 0x00, 0xe0, // STA $E000 (解除)
+            0x8d, 0x01, 0xe0, // STA $E001
+            0x40, // RTI
+        ];
+        // 最後の8KBバンクは$E000に固定
+        prg[0x6000..0x6000 + program.len()].copy_from_slice(&program);
+        prg[0x7ffa..0x8000].copy_from_slice(&[0x21, 0xe0, 0x00, 0xe0, 0x19, 0xe0]);
+        RomBuilder::new().mapper(4).prg(&prg).chr(&[0; 0x2000]).build()
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        let mut emulator = Emulator::new(mmc3_irq_counter_rom()).unwrap();
+        let mut joypad = Joypad::new();
+
+        // 1フレームで描画中のA12の立ち上がりは241回、17回ごとにIRQ
+        emulator.step_frame(&mut joypad).unwrap();
+        assert_eq!(emulator.cpu_mut().mem_read(0x10), 241 / 17);
+        emulator.step_frame(&mut joypad).unwrap();
+        assert!(emulator.cpu_mut().mem_read(0x10) > 241 / 17);
+    }
+
+    #[test]
+    fn test_step_frame() {
+        let mut emulator = Emulator::new(nmi_counter_rom()).unwrap();
+        let mut joypad = Joypad::new();
+
+        for _ in 0..3 {
+            let frame = emulator.step_frame(&mut joypad).unwrap();
+            assert_eq!(frame.data.len(), 256 * 240 * 3);
+        }
+        assert_eq!(emulator.cpu_mut().mem_read(0x10), 3);
+    }
+
+    #[test]
+    fn test_step_frame_reports_brk() {
+        // LDA #$01 / STA $10 / BRK
+        let mut prg = vec![0; 0x8000];
+        prg[0..5].copy_from_slice(&[0xa9, 0x01, 0x85, 0x10, 0x00]);
+        prg[0x7ffc] = 0x00;
+        prg[0x7ffd] = 0x80;
+        let mut emulator = Emulator::new(RomBuilder::new().prg(&prg).build()).unwrap();
+        let mut joypad = Joypad::new();
+
+        assert_eq!(emulator.step_frame(&mut joypad).err(), Some(RunStop::Break));
+        assert_eq!(emulator.cpu_mut().mem_read(0x10), 1);
+        // 描き終えていないフレームは数えない
+        assert_eq!(emulator.stats().frames, 0);
+        assert_eq!(emulator.step_frame(&mut joypad).err(), Some(RunStop::Break));
+    }
+
+    #[test]
+    fn test_take_samples_does_not_accumulate() {
+        let mut emulator = Emulator::new(nmi_counter_rom()).unwrap();
+        let mut joypad = Joypad::new();
+
+        // 取り出さずに進めても1フレーム分しか残らない
+        for _ in 0..10 {
+            emulator.step_frame(&mut joypad).unwrap();
+        }
+        let samples = emulator.take_samples();
+        assert!((700..=770).contains(&samples.len()), "got {} samples", samples.len());
+        assert!(emulator.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut emulator = Emulator::new(nmi_counter_rom()).unwrap();
+        let mut joypad = Joypad::new();
+        assert_eq!(*emulator.stats(), Stats::default());
+
+        let mut last = Stats::default();
+        for frame in 1..=3 {
+            emulator.step_frame(&mut joypad).unwrap();
+            let stats = *emulator.stats();
+            assert_eq!(stats.frames, frame);
+            assert!(stats.instructions > 0);
+            // 1フレームは約89342 PPUサイクル。命令の途中で区切れないので少し前後する
+            assert!((stats.ppu_cycles as i64 - 341 * 262).abs() < 30, "{}", stats.ppu_cycles);
+            assert!(stats.total_instructions > last.total_instructions);
+            assert!(stats.total_ppu_cycles > last.total_ppu_cycles);
+            last = stats;
+        }
+    }
+
+    #[test]
+    fn test_frame_buffer() {
+        let mut emulator = Emulator::new(nmi_counter_rom()).unwrap();
+        emulator.step_frame(&mut Joypad::new()).unwrap();
+        assert_eq!(emulator.frame_buffer().len(), 256 * 240 * 3);
+        assert_eq!(emulator.frame_buffer(), emulator.frame().data.as_slice());
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut emulator = Emulator::new(nmi_counter_rom()).unwrap();
+        let mut joypad = Joypad::new();
+        emulator.step_frame(&mut joypad).unwrap();
+        emulator.step_frame(&mut joypad).unwrap();
+
+        let state = emulator.save_state();
+        emulator.step_frame(&mut joypad).unwrap();
+        emulator.step_frame(&mut joypad).unwrap();
+        assert_eq!(emulator.cpu_mut().mem_read(0x10), 4);
+
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.cpu_mut().mem_read(0x10), 2);
+        emulator.step_frame(&mut joypad).unwrap();
+        assert_eq!(emulator.cpu_mut().mem_read(0x10), 3);
+        assert_eq!(emulator.save_state().len(), state.len());
+    }
+
+    // NMIごとに$FEの値を背景色($3F00)にする
+    fn backdrop_from_fe_rom() -> Rom {
+        let mut prg = vec![0; 0x8000];
+        let program = [
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000
+            0x4c, 0x05, 0x80, // JMP $8005
+            0xa9, 0x3f, 0x8d, 0x06, 0x20, // $8008: LDA #$3F / STA $2006
+            0xa9, 0x00, 0x8d, 0x06, 0x20, // LDA #$00 / STA $2006
+            0xa5, 0xfe, // LDA $FE
+            0x29, 0x3f, // AND #$3F
+            0x8d, 0x07, 0x20, // STA $2007
+            0x40, // RTI
+        ];
+        prg[0..program.len()].copy_from_slice(&program);
+        prg[0x7ffa..0x8000].copy_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        RomBuilder::new().prg(&prg).build()
+    }
+
+    fn frame_hashes(emulator: &mut Emulator, frames: usize) -> Vec<u64> {
+        let mut joypad = Joypad::new();
+        (0..frames).map(|_| emulator.step_frame(&mut joypad).unwrap().hash()).collect()
+    }
+
+    #[test]
+    fn test_seeded_entropy_is_reproducible() {
+        let run = |seed| {
+            let mut emulator = Emulator::new(backdrop_from_fe_rom()).unwrap();
+            emulator.set_entropy(Some((0xfe, seed)));
+            frame_hashes(&mut emulator, 8)
+        };
+        let hashes = run(42);
+        assert_eq!(run(42), hashes);
+        assert_ne!(run(43), hashes);
+        // 毎フレーム違う値が入っている
+        assert!(hashes.windows(2).any(|pair| pair[0] != pair[1]));
+
+        // セーブステートから再開しても同じ乱数が続く
+        let mut emulator = Emulator::new(backdrop_from_fe_rom()).unwrap();
+        emulator.set_entropy(Some((0xfe, 42)));
+        frame_hashes(&mut emulator, 3);
+        let state = emulator.save_state();
+        emulator.set_entropy(None);
+        emulator.load_state(&state).unwrap();
+        assert_eq!(frame_hashes(&mut emulator, 5), hashes[3..]);
+    }
+
+    #[test]
+    fn test_load_state_rejects_garbage() {
+        let mut emulator = Emulator::new(nmi_counter_rom()).unwrap();
+        assert_eq!(emulator.load_state(b"NOPE\x01"), Err("not a save state".to_string()));
+        assert_eq!(
+            emulator.load_state(b"NESS\x63"),
+            Err("unsupported save state version 99".to_string())
+        );
+    }
+
+    #[test]
+    fn test_load_state_rejects_previous_version() {
+        let mut emulator = Emulator::new(nmi_counter_rom()).unwrap();
+        emulator.step_frame(&mut Joypad::new()).unwrap();
+        let mut state = emulator.save_state();
+        // 並びが変わる前の版のステートは、ずれたまま読まずに弾く
+        state[4] = SAVE_STATE_VERSION - 1;
+        assert_eq!(
+            emulator.load_state(&state),
+            Err(format!("unsupported save state version {}", SAVE_STATE_VERSION - 1))
+        );
+        state[4] = SAVE_STATE_VERSION;
+        assert_eq!(emulator.load_state(&state), Ok(()));
+    }
+}
@@ -1,4 +1,5 @@
 use bitflags::bitflags;
+use crate::savestate::{StateReader, StateWriter};
 
 bitflags! {
     // https://wiki.nesdev.com/w/index.php/Controller_reading_code
@@ -14,11 +15,37 @@ bitflags! {
     }
 }
 
+// アナログスティックはこれより倒したときだけ方向キーとして扱う
+pub const AXIS_DEADZONE: i16 = 8000;
+
+// スティックの軸の値を、マイナス側とプラス側の方向キーの押下状態にする
+pub fn axis_to_buttons(value: i16, negative: JoypadButton, positive: JoypadButton) -> [(JoypadButton, bool); 2] {
+    [(negative, value < -AXIS_DEADZONE), (positive, value > AXIS_DEADZONE)]
+}
+
+// 上下・左右の同時押し (実機の十字キーでは押せない) をゲームにどう見せるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OppositeDirections {
+    // そのまま両方押されている
+    #[default]
+    PassThrough,
+    // 両方離されている
+    Cancel,
+    // 後から押した方だけ
+    LastPressed,
+}
+
 #[derive(Clone, Copy)]
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_status: JoypadButton,
+    // ストローブを1から0にした時点のボタン。読み出しはここから1ビットずつ出す
+    latched: u8,
+    opposite_directions: OppositeDirections,
+    // 左右・上下それぞれで最後に押された方
+    last_horizontal: JoypadButton,
+    last_vertical: JoypadButton,
 }
 
 impl Joypad {
@@ -27,31 +54,95 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_status: JoypadButton::from_bits_truncate(0),
+            latched: 0,
+            opposite_directions: OppositeDirections::default(),
+            last_horizontal: JoypadButton::RIGHT,
+            last_vertical: JoypadButton::DOWN,
         }
     }
 
     pub fn write(&mut self, data: u8) {
-        self.strobe = data & 1 == 1;
-        if self.strobe {
-            self.button_index = 0
+        // ストローブが1のあいだはボタンを読み込み続け、0にしたときの状態が残る
+        if self.strobe || data & 1 == 1 {
+            self.latched = self.buttons_seen_by_game();
+            self.button_index = 0;
         }
+        self.strobe = data & 1 == 1;
     }
 
     pub fn read(&mut self) -> u8 {
+        // ストローブ中は常に今のAボタン
+        if self.strobe {
+            return self.buttons_seen_by_game() & 1;
+        }
         if self.button_index > 7 {
             return 1;
         }
 
-        let response = (self.button_status.bits & (1 << self.button_index)) >> self.button_index;
-        if !self.strobe && self.button_index <= 7 {
-            self.button_index += 1;
-        }
+        let response = (self.latched >> self.button_index) & 1;
+        self.button_index += 1;
         response
     }
 
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        if pressed && !self.button_status.contains(button) {
+            if button.intersects(JoypadButton::LEFT | JoypadButton::RIGHT) {
+                self.last_horizontal = button & (JoypadButton::LEFT | JoypadButton::RIGHT);
+            }
+            if button.intersects(JoypadButton::UP | JoypadButton::DOWN) {
+                self.last_vertical = button & (JoypadButton::UP | JoypadButton::DOWN);
+            }
+        }
         self.button_status.set(button, pressed);
     }
+
+    pub fn set_opposite_directions(&mut self, mode: OppositeDirections) {
+        self.opposite_directions = mode;
+    }
+
+    // 同時押しの設定を反映したボタン
+    fn buttons_seen_by_game(&self) -> u8 {
+        let mut buttons = self.button_status;
+        for (pair, last) in [
+            (JoypadButton::LEFT | JoypadButton::RIGHT, self.last_horizontal),
+            (JoypadButton::UP | JoypadButton::DOWN, self.last_vertical),
+        ] {
+            if !buttons.contains(pair) {
+                continue;
+            }
+            match self.opposite_directions {
+                OppositeDirections::PassThrough => {}
+                OppositeDirections::Cancel => buttons.remove(pair),
+                OppositeDirections::LastPressed => buttons.remove(pair - last),
+            }
+        }
+        buttons.bits
+    }
+
+    // 全ボタンの押下状態。ビットの並びは$4016で読める順 (bit0がA、bit7が右)
+    pub fn snapshot(&self) -> u8 {
+        self.button_status.bits
+    }
+
+    // ボタンをまとめて設定する。ストローブや読み出し位置はそのまま
+    pub fn restore(&mut self, buttons: u8) {
+        self.button_status = JoypadButton::from_bits_truncate(buttons);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.strobe);
+        w.write_u8(self.button_index);
+        w.write_u8(self.button_status.bits);
+        w.write_u8(self.latched);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.strobe = r.read_bool()?;
+        self.button_index = r.read_u8()?;
+        self.button_status = JoypadButton::from_bits_truncate(r.read_u8()?);
+        self.latched = r.read_u8()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -68,6 +159,87 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_reads_use_state_latched_at_strobe() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoypadButton::DOWN, true);
+        joypad.write(1);
+        joypad.write(0);
+
+        assert_eq!(joypad.read(), 1);
+        // 読んでいる途中で押し変えても、ストローブ時の状態が出てくる
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, false);
+        joypad.set_button_pressed_status(JoypadButton::DOWN, false);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        let bits: Vec<u8> = (1..8).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![0, 0, 0, 0, 1, 0, 0]);
+
+        // 次のストローブで今の状態が読まれる
+        joypad.write(1);
+        joypad.write(0);
+        let bits: Vec<u8> = (0..8).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    fn read_all(joypad: &mut Joypad) -> u8 {
+        joypad.write(1);
+        joypad.write(0);
+        (0..8).fold(0, |bits, i| bits | joypad.read() << i)
+    }
+
+    #[test]
+    fn test_opposite_directions() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::LEFT, true);
+        joypad.set_button_pressed_status(JoypadButton::RIGHT, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        assert_eq!(read_all(&mut joypad), 0b1100_0001);
+
+        joypad.set_opposite_directions(OppositeDirections::Cancel);
+        assert_eq!(read_all(&mut joypad), 0b0000_0001);
+        // 押されている状態そのものは変わらない
+        assert_eq!(joypad.snapshot(), 0b1100_0001);
+
+        joypad.set_opposite_directions(OppositeDirections::LastPressed);
+        assert_eq!(read_all(&mut joypad), 0b1000_0001);
+        joypad.set_button_pressed_status(JoypadButton::LEFT, false);
+        joypad.set_button_pressed_status(JoypadButton::LEFT, true);
+        assert_eq!(read_all(&mut joypad), 0b0100_0001);
+        joypad.set_button_pressed_status(JoypadButton::UP, true);
+        joypad.set_button_pressed_status(JoypadButton::DOWN, true);
+        assert_eq!(read_all(&mut joypad), 0b0110_0001);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::START, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        assert_eq!(joypad.snapshot(), 0b0000_1001);
+
+        let mut other = Joypad::new();
+        other.restore(0b1010_0010);
+        assert_eq!(other.snapshot(), 0b1010_0010);
+
+        // シリアル読み出しでも同じ順に見える
+        other.write(1);
+        other.write(0);
+        let bits: Vec<u8> = (0..8).map(|_| other.read()).collect();
+        assert_eq!(bits, vec![0, 1, 0, 0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_axis_to_buttons() {
+        let horizontal = |value| axis_to_buttons(value, JoypadButton::LEFT, JoypadButton::RIGHT);
+
+        assert_eq!(horizontal(0), [(JoypadButton::LEFT, false), (JoypadButton::RIGHT, false)]);
+        assert_eq!(horizontal(AXIS_DEADZONE), [(JoypadButton::LEFT, false), (JoypadButton::RIGHT, false)]);
+        assert_eq!(horizontal(-AXIS_DEADZONE), [(JoypadButton::LEFT, false), (JoypadButton::RIGHT, false)]);
+        assert_eq!(horizontal(AXIS_DEADZONE + 1), [(JoypadButton::LEFT, false), (JoypadButton::RIGHT, true)]);
+        assert_eq!(horizontal(i16::MIN), [(JoypadButton::LEFT, true), (JoypadButton::RIGHT, false)]);
+    }
+
     #[test]
     fn test_strobe_mode_on_off() {
         let mut joypad = Joypad::new();
@@ -77,6 +249,9 @@ mod test {
         joypad.set_button_pressed_status(JoypadButton::LEFT, true);
         joypad.set_button_pressed_status(JoypadButton::SELECT, true);
         joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        // ボタンはストローブしたときに読み込まれる
+        joypad.write(1);
+        joypad.write(0);
 
         for _ in 0..=1 {
             assert_eq!(joypad.read(), 0);
@@ -0,0 +1,9 @@
+pub mod emu;
+pub mod ppu_emu;
+pub mod render;
+pub mod render_screen;
+pub mod joypad;
+pub mod apu_emu;
+pub mod savestate;
+pub mod emulator;
+pub mod test_rom;
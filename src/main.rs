@@ -1,32 +1,39 @@
-mod emu;
-mod ppu_emu;
-mod render;
-mod render_screen;
-mod joypad;
-
-use emu::cpu::CPU;
-use emu::bus::Bus;
-use emu::cartridge::Rom;
-//use emu::trace::trace;
-use ppu_emu::ppu::NesPPU;
-use render::frame::Frame;
+use nes_emu::emu::cpu::CPU;
+use nes_emu::emu::bus::Bus;
+use nes_emu::emu::cartridge::Rom;
+//use nes_emu::emu::trace::trace;
+use nes_emu::ppu_emu::ppu::NesPPU;
+use nes_emu::render::frame::Frame;
+use nes_emu::render::scale::{viewport, ScaleMode};
+use nes_emu::{joypad, render_screen};
+use sdl2::controller::{Axis, Button};
 use sdl2::event::Event;
 //use sdl2::EventPump;
 use sdl2::keyboard::Keycode;
 //use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
 use std::collections::HashMap;
 
+const WINDOW_SCALE: u32 = 3;
+const SCALE_MODE: ScaleMode = ScaleMode::Integer;
+
 fn main() {
     //init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem.window("Tile viewer", (256.0 * 3.0) as u32, (242.0 * 3.0) as u32)
-                                .position_centered().build().unwrap();
+    let window = video_subsystem.window("Tile viewer", 256 * WINDOW_SCALE, 240 * WINDOW_SCALE)
+                                .position_centered().resizable().build().unwrap();
 
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+
+    // 起動時に繋がっているゲームパッドを開く。後から繋いだものはイベントで開く
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+    let mut controllers: Vec<_> = (0..controller_subsystem.num_joysticks().unwrap_or(0))
+        .filter(|i| controller_subsystem.is_game_controller(*i))
+        .filter_map(|i| controller_subsystem.open(i).ok())
+        .collect();
 
     let creator = canvas.texture_creator();
     let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, 256, 240).unwrap();
@@ -34,10 +41,12 @@ fn main() {
     //load the game
     let bytes: Vec<u8> = std::fs::read("./Roms/cyo.nes").unwrap();
     //nestestは動くようになった（非公式命令でエラ＝がでる）
-    //let bytes: Vec<u8> = std::fs::read("./nestest.nes").unwrap();
+    //let bytes: Vec<u8> = std::fs::read("./tests/roms/nestest.nes").unwrap();
     let rom = Rom::new(&bytes).unwrap();
+    println!("{}", rom.info());
 
-    let mut frame = Frame::new();
+    // 上下8ラインはテレビでは見えていなかったので隠す
+    let mut frame = Frame::with_overscan(8, 8, 0, 0);
 
     let mut key_map = HashMap::new();
     key_map.insert(Keycode::Down, joypad::JoypadButton::DOWN);
@@ -49,10 +58,25 @@ fn main() {
     key_map.insert(Keycode::A, joypad::JoypadButton::BUTTON_A);
     key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
 
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut joypad::Joypad| {
+    let mut button_map = HashMap::new();
+    button_map.insert(Button::DPadDown, joypad::JoypadButton::DOWN);
+    button_map.insert(Button::DPadUp, joypad::JoypadButton::UP);
+    button_map.insert(Button::DPadRight, joypad::JoypadButton::RIGHT);
+    button_map.insert(Button::DPadLeft, joypad::JoypadButton::LEFT);
+    button_map.insert(Button::Back, joypad::JoypadButton::SELECT);
+    button_map.insert(Button::Start, joypad::JoypadButton::START);
+    button_map.insert(Button::A, joypad::JoypadButton::BUTTON_A);
+    button_map.insert(Button::B, joypad::JoypadButton::BUTTON_B);
+
+    // NMIを使わないゲームもあるので、vblankに入るたびに描画する
+    let mut bus = Bus::new(rom, |_ppu: &NesPPU, _joypad: &mut joypad::Joypad| {}).unwrap();
+    bus.set_end_of_frame_callback(move |ppu: &NesPPU, joypad: &mut joypad::Joypad, _samples: &[f32]| {
         render_screen::render(ppu, &mut frame);
         texture.update(None, &frame.data, 256 * 3).unwrap();
-        canvas.copy(&texture, None, None).unwrap();
+        let (window_width, window_height) = canvas.output_size().unwrap();
+        let vp = viewport(SCALE_MODE, window_width, window_height);
+        canvas.clear();
+        canvas.copy(&texture, None, Rect::new(vp.x, vp.y, vp.width, vp.height)).unwrap();
         canvas.present();
 
         for event in event_pump.poll_iter() {
@@ -71,6 +95,31 @@ fn main() {
                         joypad.set_button_pressed_status(*key, false);
                     }
                 },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Ok(controller) = controller_subsystem.open(which) {
+                        controllers.push(controller);
+                    }
+                },
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(key) = button_map.get(&button) {
+                        joypad.set_button_pressed_status(*key, true);
+                    }
+                },
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(key) = button_map.get(&button) {
+                        joypad.set_button_pressed_status(*key, false);
+                    }
+                },
+                Event::ControllerAxisMotion { axis, value, .. } => {
+                    let buttons = match axis {
+                        Axis::LeftX => joypad::axis_to_buttons(value, joypad::JoypadButton::LEFT, joypad::JoypadButton::RIGHT),
+                        Axis::LeftY => joypad::axis_to_buttons(value, joypad::JoypadButton::UP, joypad::JoypadButton::DOWN),
+                        _ => continue,
+                    };
+                    for (key, pressed) in buttons {
+                        joypad.set_button_pressed_status(key, pressed);
+                    }
+                },
                 _ => {}
             }
         }
@@ -83,7 +132,7 @@ fn main() {
     let bus = Bus::new(rom);
     let mut cpu = CPU::new(bus);
     cpu.reset();
-    cpu.program_counter = 0xC000;
+    cpu.set_pc(0xC000);
 
     let mut screen_state = [0 as u8; 32 * 3 * 32];
     let mut rng = rand::thread_rng();
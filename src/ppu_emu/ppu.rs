@@ -1,26 +1,77 @@
-use crate::emu::cartridge::Mirroring;
+use crate::emu::cartridge::{Mirroring, Rom};
+use crate::emu::mapper::{nrom::Nrom, SharedMapper};
 use crate::ppu_emu::registers::addr::AddrRegister;
 use crate::ppu_emu::registers::control::ControlRegister;
 use crate::ppu_emu::registers::mask::MaskRegister;
 use crate::ppu_emu::registers::scroll::ScrollRegister;
 use crate::ppu_emu::registers::status::StatusRegister;
+use crate::savestate::{StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SPRITES_PER_LINE: usize = 8;
+
+// NTSCのタイミング
+// https://www.nesdev.org/wiki/PPU_rendering
+// 0-239が表示、240は何もしないポストレンダー、241-260がvblank、261がプリレンダー
+pub const VISIBLE_SCANLINES: u16 = 240;
+pub const VBLANK_START_SCANLINE: u16 = 241;
+pub const PRE_RENDER_SCANLINE: u16 = 261;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const DOTS_PER_SCANLINE: usize = 341;
+
+// コールバックに渡すPPUの位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpuTiming {
+    pub scanline: i16,
+    pub cycle: u16,
+    pub frame: u64,
+}
 
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
-    pub mirroring: Mirroring,
+    mapper: SharedMapper,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
     pub scroll: ScrollRegister,
     pub addr: AddrRegister,
-    pub vram: [u8; 2048],
+    // 本体の2KB。4画面のカートリッジならカートリッジ側の2KBを足して4KB
+    pub vram: Vec<u8>,
     pub oam_addr: u8,
     pub oam_data: [u8; 256],
+    // スプライト評価でそのラインに載るスプライトを8個までコピーする先
+    secondary_oam: [u8; 32],
+    secondary_count: usize,
     pub palette_table: [u8; 32],
+    // 描画中のラインでパレットを書き換えたときの (そのパレットを使い始めるライン, パレット)
+    // 先頭はそのフレームを描き始めたときのパレット。書き換えがなければ空
+    palette_changes: Vec<(usize, [u8; 32])>,
+    // vblankに入ったときにpalette_changesをここへ移す (描画はこちらを見る)
+    frame_palettes: Vec<(usize, [u8; 32])>,
     pub internal_data_buf: u8,
     scanline: u16,
     cycles: usize,
+    frame_count: u64,
     pub nmi_interrupt: Option<u8>,
+    // NMIが無効でも、vblankに入ったら立つ
+    vblank_started: bool,
+    // trueなら1ラインに9個以上のスプライトも描く (ちらつき防止)
+    pub no_sprite_limit: bool,
+    // vblank開始と$2002の読み込みが重なったときの挙動 (互換性用)
+    //   true:  実機の競合を再現する。ドット0で読むとそのフレームはvblankフラグもNMIも立たず、
+    //          ドット1-2で読むとフラグは読めるがNMIは出ない
+    //          (blarggのppu_vbl_nmiの06-suppressionが見ている挙動)
+    //   false: 単純なモデル。読んだ時点のフラグを返して消すだけでNMIには影響しない
+    //          (以前からの挙動。06-suppressionは通らない)
+    // CPUは命令単位でPPUを進めるので、02-vbl_set_timeのように1ドット単位の
+    // タイミングを見るテストはどちらでも通らない
+    pub vblank_race_quirk: bool,
+    vblank_suppressed: bool,
+    // デバッグ用。Someならカートリッジのミラーリングの代わりに使う
+    mirroring_override: Option<Mirroring>,
+    // デバッグ用のレイヤー表示。PPUMASKとは別に、描画するときだけ効く
+    show_background: bool,
+    show_sprites: bool,
 }
 
 pub trait PPU {
@@ -42,53 +93,267 @@ impl NesPPU {
         NesPPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
     }
 
+    // CHRとミラーリングだけを持つNROMのカートリッジをつなぐ
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let rom = Rom {
+            prg_rom: vec![0; 0x4000].into(),
+            chr_rom: chr_rom.into(),
+            mapper: 0,
+            screen_mirroring: mirroring,
+            battery: false,
+        };
+        NesPPU::with_mapper(Rc::new(RefCell::new(Nrom::new(rom))))
+    }
+
+    pub fn with_mapper(mapper: SharedMapper) -> Self {
+        let vram_size = if mapper.borrow().mirroring() == Mirroring::FOUR_SCREEN { 0x1000 } else { 0x800 };
         NesPPU {
-            chr_rom,
-            mirroring,
+            mapper,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
             scroll: ScrollRegister::new(),
             addr: AddrRegister::new(),
-            vram: [0; 2048],
+            vram: vec![0; vram_size],
             oam_addr: 0,
             oam_data: [0; 256],
+            secondary_oam: [0xff; 32],
+            secondary_count: 0,
             palette_table: [0; 32],
+            palette_changes: Vec::new(),
+            frame_palettes: Vec::new(),
             internal_data_buf: 0,
             scanline: 0,
             cycles: 0,
+            frame_count: 0,
             nmi_interrupt: None,
+            vblank_started: false,
+            no_sprite_limit: false,
+            vblank_race_quirk: false,
+            vblank_suppressed: false,
+            mirroring_override: None,
+            show_background: true,
+            show_sprites: true,
         }
     }
 
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
+        let mut frame_done = false;
+        for _ in 0..cycles {
+            if self.dot() {
+                frame_done = true;
+            }
+        }
+        frame_done
+    }
+
+    // CPUとは関係なく1ドットだけ進める。このドットでNMIが立ったらtrue (テスト用)
+    pub fn step(&mut self) -> bool {
+        let nmi_before = self.nmi_interrupt.is_some();
+        self.dot();
+        !nmi_before && self.nmi_interrupt.is_some()
+    }
+
+    // 1ドット進める。フレームの最後に達したらtrue
+    fn dot(&mut self) -> bool {
+        self.cycles += 1;
+        if self.cycles >= DOTS_PER_SCANLINE {
             if self.is_sprite_0_hit(self.cycles) {
                 self.status.set_sprite_zero_hit(true);
             }
-            self.cycles = self.cycles - 341;
+            self.cycles = 0;
             self.scanline += 1;
 
-            if self.scanline == 241 {
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
-                }
-            }
-
-            if self.scanline >= 262 {
+            if self.scanline >= SCANLINES_PER_FRAME {
                 self.scanline = 0;
+                self.frame_count += 1;
                 self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
                 return true;
             }
+        }
+
+        // プリレンダーラインのドット1でvblank・スプライト0ヒット・オーバーフローを消す
+        if self.scanline == PRE_RENDER_SCANLINE && self.cycles == 1 {
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+
+        // 描画中はドット1から背景、257からスプライトのパターンを読むので、
+        // そのアドレスをマッパーに見せる (MMC3はA12の立ち上がりでスキャンラインを数える)
+        if (self.cycles == 1 || self.cycles == 257) && self.is_rendering() {
+            let addr = if self.cycles == 1 {
+                self.ctrl.bknd_pattern_addr()
+            } else {
+                self.ctrl.sprt_pattern_addr()
+            };
+            self.mapper.borrow_mut().notify_ppu_addr(addr);
+        }
+
+        if self.scanline < VISIBLE_SCANLINES && self.is_rendering() {
+            self.evaluate_sprites();
+        }
+
+        // スプライト評価が終わるドット257で、9個目があればオーバーフロー
+        if self.cycles == 257
+            && self.scanline < VISIBLE_SCANLINES
+            && self.is_rendering()
+            && self.sprites_in_range(self.scanline as usize).nth(SPRITES_PER_LINE).is_some()
+        {
+            self.status.set_sprite_overflow(true);
+        }
+
+        // スプライトのタイルを読むドット257-320の間、OAMADDRは0に戻される
+        // (ここでOAMADDRを0にしておかないと次のフレームのスプライトがずれる)
+        if (257..=320).contains(&self.cycles)
+            && (self.scanline < VISIBLE_SCANLINES || self.scanline == PRE_RENDER_SCANLINE)
+            && self.is_rendering()
+        {
+            self.oam_addr = 0;
+        }
+
+        if self.scanline == VBLANK_START_SCANLINE && self.cycles == 1 {
+            self.vblank_started = true;
+            self.frame_palettes = std::mem::take(&mut self.palette_changes);
+        }
+        if self.scanline == VBLANK_START_SCANLINE && self.cycles == 1 && std::mem::take(&mut self.vblank_suppressed) {
+            return false;
+        }
+        if self.scanline == VBLANK_START_SCANLINE && self.cycles == 1 {
+            self.status.set_vblank_status(true);
+            if self.ctrl.generate_vblank_nmi() {
+                self.nmi_interrupt = Some(1);
+            }
+        }
+        false
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        match &self.mirroring_override {
+            Some(mirroring) => mirroring.clone(),
+            None => self.mapper.borrow().mirroring(),
+        }
+    }
+
+    pub fn set_mirroring_override(&mut self, mirroring: Option<Mirroring>) {
+        if mirroring == Some(Mirroring::FOUR_SCREEN) {
+            self.vram.resize(0x1000, 0);
+        }
+        self.mirroring_override = mirroring;
+    }
 
+    // $2000/$2400/$2800/$2C00のネームテーブル(属性テーブル込みで1KB)
+    pub fn nametable(&self, addr: u16) -> &[u8] {
+        let start = self.mirror_vram_addr(addr) as usize & !0x3ff;
+        &self.vram[start..start + 0x400]
+    }
+
+    // ネームテーブルnt (0-3) の (tile_x, tile_y) にあるタイル番号と、属性テーブルで選ばれた背景パレット (0-3)
+    pub fn nametable_tile(&self, nt: usize, tile_x: usize, tile_y: usize) -> (u8, u8) {
+        assert!(nt < 4 && tile_x < 32 && tile_y < 30, "no tile ({}, {}) in nametable {}", tile_x, tile_y, nt);
+        let nametable = self.nametable(0x2000 + nt as u16 * 0x400);
+        let index = nametable[tile_y * 32 + tile_x];
+        // 属性1バイトが4x4タイルを受け持ち、2x2タイルごとに2ビットずつ使う
+        let attr = nametable[0x3c0 + tile_y / 4 * 8 + tile_x / 4];
+        let shift = (tile_y % 4 / 2 * 2 + tile_x % 4 / 2) * 2;
+        (index, (attr >> shift) & 0b11)
+    }
+
+    pub fn set_show_background(&mut self, show: bool) {
+        self.show_background = show;
+    }
+
+    pub fn set_show_sprites(&mut self, show: bool) {
+        self.show_sprites = show;
+    }
+
+    pub fn show_background(&self) -> bool {
+        self.show_background
+    }
+
+    pub fn show_sprites(&self) -> bool {
+        self.show_sprites
+    }
+
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.mapper.borrow().read_chr(addr)
+    }
+
+    // パターンテーブルの1タイル分(16バイト)
+    pub fn chr_tile(&self, addr: u16) -> [u8; 16] {
+        let mapper = self.mapper.borrow();
+        let mut tile = [0; 16];
+        for (i, byte) in tile.iter_mut().enumerate() {
+            *byte = mapper.read_chr(addr + i as u16);
         }
-        return false;
+        tile
+    }
+
+    pub fn scanline(&self) -> i16 {
+        self.scanline as i16
+    }
+
+    pub fn cycle(&self) -> u16 {
+        self.cycles as u16
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn timing(&self) -> PpuTiming {
+        PpuTiming { scanline: self.scanline(), cycle: self.cycle(), frame: self.frame_count }
+    }
+
+    fn sprites_in_range(&self, y: usize) -> impl Iterator<Item = usize> + '_ {
+        let height = self.ctrl.sprite_size() as usize;
+        (0..64).filter(move |i| {
+            let top = self.oam_data[i * 4] as usize;
+            top <= y && y < top + height
+        })
+    }
+
+    // スキャンラインyに描かれるスプライトのOAM番号 (優先度順)
+    pub fn sprites_on_scanline(&self, y: usize) -> Vec<usize> {
+        if self.no_sprite_limit {
+            self.sprites_in_range(y).collect()
+        } else {
+            self.sprites_in_range(y).take(SPRITES_PER_LINE).collect()
+        }
+    }
+
+    // 描画が有効で、表示中のスキャンラインかプリレンダーラインにいる
+    fn is_rendering(&self) -> bool {
+        (self.mask.show_background() || self.mask.show_sprites())
+            && (self.scanline < VISIBLE_SCANLINES || self.scanline == PRE_RENDER_SCANLINE)
+    }
+
+    // https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    // ドット1-64でセカンダリOAMを$FFで埋め、65-256でOAMを先頭から読んで範囲内のスプライトをコピーする
+    // 実機は1スプライトに2-8ドットかけるが、ここでは2ドットに1個ずつ調べる
+    // (評価より前にOAMを書き換えれば反映され、後なら反映されない)
+    fn evaluate_sprites(&mut self) {
+        match self.cycles {
+            1 => self.secondary_count = 0,
+            2..=64 if self.cycles & 1 == 0 => self.secondary_oam[self.cycles / 2 - 1] = 0xff,
+            65..=191 if self.cycles & 1 == 1 => {
+                let n = (self.cycles - 65) / 2;
+                let top = self.oam_data[n * 4] as usize;
+                let y = self.scanline as usize;
+                let in_range = top <= y && y < top + self.ctrl.sprite_size() as usize;
+                if in_range && self.secondary_count < SPRITES_PER_LINE {
+                    let dest = self.secondary_count * 4;
+                    self.secondary_oam[dest..dest + 4].copy_from_slice(&self.oam_data[n * 4..n * 4 + 4]);
+                    self.secondary_count += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 今のラインのスプライト評価の結果。空きは$FF
+    pub fn secondary_oam(&self) -> &[u8; 32] {
+        &self.secondary_oam
     }
 
     fn is_sprite_0_hit(&self, cycle: usize) -> bool {
@@ -97,23 +362,111 @@ impl NesPPU {
         (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
     }
 
-    fn increment_vram_addr(&mut self) {
+    // パレットRAMをそのまま読み書きする ($2006/$2007を通さない)
+    pub fn palette(&self) -> [u8; 32] {
+        self.palette_table
+    }
+
+    pub fn set_palette(&mut self, palette: &[u8; 32]) {
+        self.palette_table = *palette;
+    }
+
+    // 直前のフレームでラインyを描いたときのパレット。途中で書き換えていなければ今のパレット
+    pub fn palette_for_scanline(&self, y: usize) -> &[u8; 32] {
+        self.frame_palettes
+            .iter()
+            .rev()
+            .find(|(line, _)| *line <= y)
+            .map_or(&self.palette_table, |(_, palette)| palette)
+    }
+
+    fn write_palette(&mut self, addr: u16, value: u8) {
+        let visible = self.scanline < VISIBLE_SCANLINES;
+        if visible && self.palette_changes.is_empty() {
+            self.palette_changes.push((0, self.palette_table));
+        }
+        self.palette_table[Self::palette_index(addr)] = value;
+        // 描画中のラインは書き換え前の色で描き終わっているので、次のラインから効く
+        if visible {
+            self.palette_changes.push((self.scanline as usize + 1, self.palette_table));
+        }
+    }
+
+    // $3F00-$3F1Fが$3FFFまで繰り返し、スプライト側の背景色$3F10/$14/$18/$1Cは$3F00/$04/$08/$0Cと共有
+    fn palette_index(addr: u16) -> usize {
+        let index = (addr - 0x3f00) as usize % 32;
+        match index {
+            0x10 | 0x14 | 0x18 | 0x1c => index - 0x10,
+            _ => index,
+        }
+    }
+
+    // $2007の読み書きで使うアドレスを返し、vを1か32進める。読みと書きで同じ
+    fn next_vram_addr(&mut self) -> u16 {
+        let addr = self.addr.get();
         self.addr.increment(self.ctrl.vram_addr_increment());
+        addr
+    }
+
+    // 前回呼ばれてからvblankに入ったか。PPUCTRLのNMI有効ビットとは関係ない
+    pub fn poll_vblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.vblank_started)
     }
 
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.ctrl.bits());
+        w.write_u8(self.mask.bits());
+        w.write_u8(self.status.snapshot());
+        w.write_u8(self.scroll.scroll_x);
+        w.write_u8(self.scroll.scroll_y);
+        w.write_bool(self.scroll.latch);
+        self.addr.save_state(w);
+        w.write_bytes(&self.vram);
+        w.write_u8(self.oam_addr);
+        w.write_bytes(&self.oam_data);
+        w.write_bytes(&self.palette_table);
+        w.write_u8(self.internal_data_buf);
+        w.write_u16(self.scanline);
+        w.write_u64(self.cycles as u64);
+        w.write_u64(self.frame_count);
+        w.write_bool(self.nmi_interrupt.is_some());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.ctrl.update(r.read_u8()?);
+        self.mask.update(r.read_u8()?);
+        self.status = StatusRegister::from_bits_truncate(r.read_u8()?);
+        self.scroll.scroll_x = r.read_u8()?;
+        self.scroll.scroll_y = r.read_u8()?;
+        self.scroll.latch = r.read_bool()?;
+        self.addr.load_state(r)?;
+        r.read_bytes(&mut self.vram)?;
+        self.oam_addr = r.read_u8()?;
+        r.read_bytes(&mut self.oam_data)?;
+        r.read_bytes(&mut self.palette_table)?;
+        self.internal_data_buf = r.read_u8()?;
+        self.scanline = r.read_u16()?;
+        self.cycles = r.read_u64()? as usize;
+        self.frame_count = r.read_u64()?;
+        self.nmi_interrupt = if r.read_bool()? { Some(1) } else { None };
+        Ok(())
+    }
+
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b10111111111111;
         let vram_index = mirrored_vram - 0x2000;
         let name_table = vram_index / 0x400;
 
-        match (&self.mirroring, name_table) {
+        match (self.mirroring(), name_table) {
             (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
             (Mirroring::HORIZONTAL, 2) | (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+            (Mirroring::SINGLE_SCREEN_A, _) => vram_index % 0x400,
+            (Mirroring::SINGLE_SCREEN_B, _) => 0x400 + vram_index % 0x400,
             _ => vram_index
         }
     }
@@ -134,6 +487,13 @@ impl PPU for NesPPU {
 
     fn read_status(&mut self) -> u8 {
         let data = self.status.snapshot();
+        if self.vblank_race_quirk && self.scanline == VBLANK_START_SCANLINE {
+            match self.cycles {
+                0 => self.vblank_suppressed = true,
+                1 | 2 => self.nmi_interrupt = None,
+                _ => {}
+            }
+        }
         self.status.reset_vblank_status();
         self.addr.reset_latch();
         self.scroll.reset_latch();
@@ -150,55 +510,65 @@ impl PPU for NesPPU {
     }
 
     fn read_oam_data(&self) -> u8 {
+        // 描画中のドット1-64はセカンダリOAMを$FFで埋めているので、その値が見える
+        // それ以外はスプライト評価の途中の値が見えるが、評価は再現していないので主OAMを返す
+        if self.is_rendering() && (1..=64).contains(&self.cycles) {
+            return 0xff;
+        }
         self.oam_data[self.oam_addr as usize]
     }
 
+    // $2005と$2006は書き込みトグル(w)を共有している
     fn write_to_scroll(&mut self, value: u8) {
         self.scroll.write(value);
+        self.addr.flip_latch();
     }
 
     fn write_to_ppu_addr(&mut self, value: u8) {
+        if !self.scroll.latch {
+            self.ctrl.set_nametable(value >> 2);
+        }
         self.addr.update(value);
+        self.scroll.write_ppu_addr(value);
     }
 
     fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        let addr = self.next_vram_addr();
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
-            0x0000..=0x2fff => self.vram[self.mirror_vram_addr(addr) as usize] = value,
-            0x3000..=0x3eff => {},//unimplemented!("addr {:x} shouldn't be used in reallity", addr),
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
+            0..=0x1fff => {
+                let mut mapper = self.mapper.borrow_mut();
+                if mapper.chr_writable(addr) {
+                    mapper.write_chr(addr, value);
+                }
+            },
+            // $3000-$3EFFは読みと同じく$2000-$2EFFのミラー
+            0x2000..=0x3eff => {
+                let index = self.mirror_vram_addr(addr) as usize;
+                self.vram[index] = value;
             },
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize] = value,
+            0x3f00..=0x3fff => self.write_palette(addr, value),
             _ => panic!("unexpected access to mirrored space {}", addr)
         }
-        self.increment_vram_addr();
     }
 
     fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
-        self.increment_vram_addr();
+        let addr = self.next_vram_addr();
 
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.read_chr(addr);
                 result
             },
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result = self.internal_data_buf;
                 self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
                 result
             },
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
-            },
+            // パレットはすぐ返すが、バッファには裏にあるネームテーブル($2F00-$2FFF)の値が入る
             0x3f00..=0x3fff => {
-                self.palette_table[(addr - 0x3f00) as usize]
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.palette_table[Self::palette_index(addr)]
             },
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
@@ -214,6 +584,7 @@ impl PPU for NesPPU {
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::emu::mapper::test::TestMapper;
 
     #[test]
     fn test_ppu_vram_writes() {
@@ -239,6 +610,29 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_ppu_data_writes_are_contiguous() {
+        for (ctrl, step) in [(0, 1), (0b100, 32)] {
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.write_to_ctrl(ctrl);
+            ppu.write_to_ppu_addr(0x20);
+            ppu.write_to_ppu_addr(0x40);
+            for value in 1..=20 {
+                ppu.write_to_data(value);
+            }
+            assert_eq!(ppu.addr.get(), 0x2040 + 20 * step);
+
+            ppu.write_to_ppu_addr(0x20);
+            ppu.write_to_ppu_addr(0x40);
+            ppu.read_data(); //load_into_buffer
+            let values: Vec<u8> = (0..20).map(|_| ppu.read_data()).collect();
+            assert_eq!(values, (1..=20).collect::<Vec<u8>>());
+            for i in 0..20 {
+                assert_eq!(ppu.vram[0x40 + i as usize * step as usize], i + 1);
+            }
+        }
+    }
+
     #[test]
     fn test_ppu_vram_reads_cross_page() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -271,6 +665,30 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x88);
     }
 
+    #[test]
+    fn test_ppu_vram_writes_step_32() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b100);
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+
+        for (i, value) in [0x11, 0x22, 0x33, 0x44].into_iter().enumerate() {
+            assert_eq!(ppu.addr.get(), 0x2005 + 32 * i as u16);
+            ppu.write_to_data(value);
+        }
+        assert_eq!(ppu.addr.get(), 0x2005 + 32 * 4);
+        assert_eq!(ppu.vram[0x0005], 0x11);
+        assert_eq!(ppu.vram[0x0025], 0x22);
+        assert_eq!(ppu.vram[0x0045], 0x33);
+        assert_eq!(ppu.vram[0x0065], 0x44);
+
+        // $3FFFを越えると$0000側へ戻る
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0xf0);
+        ppu.write_to_data(0);
+        assert_eq!(ppu.addr.get(), 0x0010);
+    }
+
     // Horizontal: https://wiki.nesdev.com/w/index.php/Mirroring
     //   [0x2000 A ] [0x2400 a ]
     //   [0x2800 B ] [0x2C00 b ]
@@ -330,6 +748,49 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_mirroring_override() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::VERTICAL);
+        ppu.set_mirroring_override(Some(Mirroring::HORIZONTAL));
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66); //write to A
+
+        // 水平ミラーなので$2400はAと同じ
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+
+        ppu.set_mirroring_override(None);
+        assert_eq!(ppu.mirroring(), Mirroring::VERTICAL);
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0);
+    }
+
+    #[test]
+    fn test_four_screen_nametables_are_independent() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN);
+        assert_eq!(ppu.vram.len(), 0x1000);
+
+        for (i, hi) in [0x20, 0x24, 0x28, 0x2c].iter().enumerate() {
+            ppu.write_to_ppu_addr(*hi);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.write_to_data(0x10 + i as u8);
+        }
+
+        for (i, hi) in [0x20, 0x24, 0x28, 0x2c].iter().enumerate() {
+            ppu.write_to_ppu_addr(*hi);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.read_data(); //load into buffer
+            assert_eq!(ppu.read_data(), 0x10 + i as u8);
+            assert_eq!(ppu.nametable((*hi as u16) << 8)[5], 0x10 + i as u8);
+        }
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -351,6 +812,37 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_read_status_resets_half_written_addr() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x3f);
+
+        ppu.read_status();
+
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.addr.get(), 0x2305);
+    }
+
+    #[test]
+    fn test_scroll_and_addr_share_latch() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_scroll(0x10);
+
+        // second write of the shared toggle goes to the low byte
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_ppu_addr(0x23);
+        assert_eq!(ppu.addr.get(), 0x2305);
+        assert!(ppu.scroll.latch);
+
+        ppu.read_status();
+        assert!(!ppu.scroll.latch);
+        ppu.write_to_scroll(0x20);
+        ppu.write_to_scroll(0x30);
+        assert_eq!(ppu.scroll.scroll_x, 0x20);
+        assert_eq!(ppu.scroll.scroll_y, 0x30);
+    }
+
     #[test]
     fn test_ppu_vram_mirroring() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -365,6 +857,24 @@ pub mod test {
         // assert_eq!(ppu.addr.read(), 0x0306)
     }
 
+    #[test]
+    fn test_3000_mirror_reads_and_writes_agree() {
+        let mut ppu = NesPPU::new_empty_rom();
+        // $3123に書くと$2123に入る
+        ppu.write_to_ppu_addr(0x31);
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_data(0x5a);
+        assert_eq!(ppu.vram[0x0123], 0x5a);
+
+        // $3123からも$2123からも同じ値が読める
+        for hi in [0x31, 0x21] {
+            ppu.write_to_ppu_addr(hi);
+            ppu.write_to_ppu_addr(0x23);
+            ppu.read_data();
+            assert_eq!(ppu.read_data(), 0x5a);
+        }
+    }
+
     #[test]
     fn test_read_status_resets_vblank() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -376,6 +886,228 @@ pub mod test {
         assert_eq!(ppu.status.snapshot() >> 7, 0);
     }
 
+    fn tick_to(ppu: &mut NesPPU, scanline: i16, cycle: u16) {
+        while ppu.scanline() != scanline || ppu.cycle() != cycle {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_status_read_clears_only_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.status.set_vblank_status(true);
+        ppu.status.set_sprite_zero_hit(true);
+        ppu.status.set_sprite_overflow(true);
+
+        assert_eq!(ppu.read_status() & 0xe0, 0xe0);
+        // スプライト0ヒットとオーバーフローはプリレンダーラインまで残る
+        assert_eq!(ppu.read_status() & 0xe0, 0x60);
+        assert_eq!(ppu.read_status() & 0xe0, 0x60);
+    }
+
+    #[test]
+    fn test_vblank_set_at_scanline_241_cycle_1() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+
+        tick_to(&mut ppu, 241, 0);
+        assert!(!ppu.status.is_in_vblank());
+        assert!(ppu.nmi_interrupt.is_none());
+
+        ppu.tick(1);
+        assert_eq!((ppu.scanline(), ppu.cycle()), (241, 1));
+        assert!(ppu.status.is_in_vblank());
+        assert!(ppu.nmi_interrupt.is_some());
+    }
+
+    #[test]
+    fn test_step_dot_by_dot_to_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+
+        let mut nmi_at = Vec::new();
+        for _ in 0..VBLANK_START_SCANLINE as usize * DOTS_PER_SCANLINE + 1 {
+            if ppu.step() {
+                nmi_at.push((ppu.scanline(), ppu.cycle()));
+            }
+        }
+        assert_eq!((ppu.scanline(), ppu.cycle()), (241, 1));
+        assert_eq!(nmi_at, vec![(241, 1)]);
+        assert!(ppu.status.is_in_vblank());
+        assert!(ppu.nmi_interrupt.is_some());
+
+        // 次のドットでNMIが立ち直すことはない
+        assert!(!ppu.step());
+        assert!(ppu.nmi_interrupt.is_some());
+    }
+
+    #[test]
+    fn test_ppu_addr_write_sets_scroll() {
+        let mut ppu = NesPPU::new_empty_rom();
+        // 細かいXは$2005でしか変わらない
+        ppu.write_to_scroll(0x05);
+        ppu.write_to_scroll(0xff);
+
+        // t = 0b010_11_01010_01010 (細かいY=2、ネームテーブル3、粗いY=10、粗いX=10)
+        ppu.write_to_ppu_addr(0x2d);
+        ppu.write_to_ppu_addr(0x4a);
+        assert_eq!(ppu.addr.get(), 0x2d4a);
+        assert_eq!(ppu.scroll.scroll_x, 10 * 8 + 5);
+        assert_eq!(ppu.scroll.scroll_y, 10 * 8 + 2);
+        assert_eq!(ppu.ctrl.nametable_addr(), 0x2c00);
+        assert!(!ppu.scroll.latch);
+    }
+
+    #[test]
+    fn test_nametable_tile() {
+        let mut ppu = NesPPU::new(vec![0; 0x2000], Mirroring::VERTICAL);
+        // ネームテーブル1の (13, 6) にタイル$42
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0xcd);
+        ppu.write_to_data(0x42);
+        // (12-15, 4-7) の属性。(13, 6) は左下の2x2なのでbit4-5
+        ppu.write_to_ppu_addr(0x27);
+        ppu.write_to_ppu_addr(0xcb);
+        ppu.write_to_data(0b11_10_01_00);
+
+        assert_eq!(ppu.nametable_tile(1, 13, 6), (0x42, 0b10));
+        assert_eq!(ppu.nametable_tile(1, 14, 4), (0, 0b01));
+        assert_eq!(ppu.nametable_tile(1, 15, 7), (0, 0b11));
+        // 縦ミラーなので3は1と同じ
+        assert_eq!(ppu.nametable_tile(3, 13, 6), (0x42, 0b10));
+        assert_eq!(ppu.nametable_tile(0, 13, 6), (0, 0));
+    }
+
+    #[test]
+    fn test_vblank_race_quirk() {
+        for quirk in [false, true] {
+            // vblankが立つ1ドット前に読む
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.vblank_race_quirk = quirk;
+            ppu.write_to_ctrl(0b1000_0000);
+            tick_to(&mut ppu, 241, 0);
+            assert_eq!(ppu.read_status() & 0x80, 0);
+            ppu.tick(1);
+            assert_eq!(ppu.status.is_in_vblank(), !quirk);
+            assert_eq!(ppu.nmi_interrupt.is_some(), !quirk);
+
+            // vblankが立ったドットで読む
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.vblank_race_quirk = quirk;
+            ppu.write_to_ctrl(0b1000_0000);
+            tick_to(&mut ppu, 241, 1);
+            assert_eq!(ppu.read_status() & 0x80, 0x80);
+            assert_eq!(ppu.nmi_interrupt.is_some(), !quirk);
+        }
+    }
+
+    #[test]
+    fn test_status_flags_cleared_at_pre_render_line() {
+        let mut ppu = NesPPU::new_empty_rom();
+        tick_to(&mut ppu, 240, 0);
+        ppu.status.set_sprite_zero_hit(true);
+        ppu.status.set_sprite_overflow(true);
+
+        // vblankの開始では消えない
+        tick_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.status.snapshot() & 0xe0, 0xe0);
+
+        tick_to(&mut ppu, 261, 0);
+        assert_eq!(ppu.status.snapshot() & 0xe0, 0xe0);
+
+        ppu.tick(1);
+        assert_eq!((ppu.scanline(), ppu.cycle()), (261, 1));
+        assert_eq!(ppu.status.snapshot() & 0xe0, 0);
+    }
+
+    #[test]
+    fn test_frame_count() {
+        let mut ppu = NesPPU::new_empty_rom();
+        assert_eq!(ppu.frame_count(), 0);
+
+        let mut frame_done = false;
+        for _ in 0..(341 * 262 / 3) {
+            frame_done |= ppu.tick(3);
+        }
+        assert!(!frame_done);
+        assert_eq!((ppu.scanline(), ppu.cycle()), (261, 339));
+
+        assert!(ppu.tick(3));
+        assert_eq!(ppu.frame_count(), 1);
+        assert_eq!((ppu.scanline(), ppu.cycle()), (0, 1));
+    }
+
+    #[test]
+    fn test_dots_per_frame() {
+        let mut ppu = NesPPU::new_empty_rom();
+        // 描画を有効にしても、NTSCは毎フレーム341x262ドット (奇数フレームの1ドット省略はない)
+        ppu.write_to_mask(0b0001_1000);
+        for frame in 0..2 {
+            let mut dots = 0;
+            let mut visible_lines = std::collections::HashSet::new();
+            loop {
+                dots += 1;
+                if ppu.scanline() < VISIBLE_SCANLINES as i16 {
+                    visible_lines.insert(ppu.scanline());
+                }
+                if ppu.tick(1) {
+                    break;
+                }
+            }
+            assert_eq!(dots, DOTS_PER_SCANLINE * SCANLINES_PER_FRAME as usize, "frame {}", frame);
+            assert_eq!(visible_lines.len(), VISIBLE_SCANLINES as usize);
+        }
+    }
+
+    #[test]
+    fn test_ppu_data_read_buffer() {
+        let mut ppu = NesPPU::new(vec![0xc5; 0x2000], Mirroring::HORIZONTAL);
+        ppu.vram[0x0010] = 0x42;
+        ppu.write_to_ctrl(0);
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x10);
+        assert_eq!(ppu.read_data(), 0);
+        assert_eq!(ppu.read_data(), 0x42);
+
+        // CHRも同じく1回遅れる
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), ppu.vram[0x0011]);
+        assert_eq!(ppu.read_data(), 0xc5);
+    }
+
+    #[test]
+    fn test_palette_read_is_immediate_and_fills_buffer() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette_table[0x01] = 0x2c;
+        ppu.vram[0x0701] = 0x99; // $2F01 (horizontal)
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x01);
+        assert_eq!(ppu.read_data(), 0x2c);
+        assert_eq!(ppu.internal_data_buf, 0x99);
+    }
+
+    #[test]
+    fn test_palette_backdrop_mirrors() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0x2a);
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x2a);
+        assert_eq!(ppu.palette_table[0x10], 0);
+
+        // $3F20以降も32バイト単位でミラー
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x3c);
+        ppu.write_to_data(0x11);
+        assert_eq!(ppu.palette_table[0x0c], 0x11);
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -390,6 +1122,48 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_oam_read_during_vblank_and_rendering() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_oam_addr(0x20);
+        ppu.write_to_oam_data(0x5a);
+        ppu.write_to_mask(0b0001_1000);
+
+        // 描画中にOAMADDRは0に戻るので、ゲームと同じくvblank中に設定する
+        tick_to(&mut ppu, 241, 10);
+        ppu.write_to_oam_addr(0x20);
+        assert_eq!(ppu.read_oam_data(), 0x5a);
+        assert_eq!(ppu.oam_addr, 0x20);
+
+        tick_to(&mut ppu, 5, 10);
+        assert_eq!(ppu.read_oam_data(), 0xff);
+
+        // 描画が無効ならいつでも主OAMが読める
+        ppu.write_to_mask(0);
+        ppu.write_to_oam_addr(0x20);
+        assert_eq!(ppu.read_oam_data(), 0x5a);
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.oam_data = [0xff; 256];
+        for i in 0..9 {
+            ppu.oam_data[i * 4] = 50;
+        }
+        ppu.write_to_mask(0b0001_0000);
+        ppu.status.set_sprite_overflow(false);
+
+        tick_to(&mut ppu, 50, 256);
+        assert_eq!(ppu.status.snapshot() & 0b0010_0000, 0);
+        tick_to(&mut ppu, 50, 257);
+        assert_eq!(ppu.status.snapshot() & 0b0010_0000, 0b0010_0000);
+        assert_eq!(ppu.sprites_on_scanline(50).len(), 8);
+
+        ppu.no_sprite_limit = true;
+        assert_eq!(ppu.sprites_on_scanline(50).len(), 9);
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -405,8 +1179,60 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x88);
 
         ppu.write_to_oam_addr(0x10);
-        ppu.write_to_oam_addr(0x77);
+        assert_eq!(ppu.read_oam_data(), 0x77);
         ppu.write_to_oam_addr(0x11);
-        ppu.write_to_oam_addr(0x66);
+        assert_eq!(ppu.read_oam_data(), 0x66);
+    }
+
+    #[test]
+    fn test_secondary_oam_holds_sprites_in_range() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.oam_data = [0xff; 256];
+        ppu.oam_data[0..4].copy_from_slice(&[10, 1, 2, 3]);
+        ppu.oam_data[4..8].copy_from_slice(&[50, 4, 5, 6]);
+        ppu.oam_data[8..12].copy_from_slice(&[12, 7, 8, 9]);
+        ppu.write_to_mask(0b0001_1000);
+
+        tick_to(&mut ppu, 12, 257);
+        let mut expected = [0xff; 32];
+        expected[0..8].copy_from_slice(&[10, 1, 2, 3, 12, 7, 8, 9]);
+        assert_eq!(ppu.secondary_oam(), &expected);
+
+        // 評価の途中で書き換えると、まだ読んでいないスプライトだけ反映される
+        tick_to(&mut ppu, 13, 68);
+        ppu.oam_data[0] = 0xff;
+        ppu.oam_data[8] = 0xff;
+        tick_to(&mut ppu, 13, 257);
+        assert_eq!(ppu.secondary_oam()[0..8], [10, 1, 2, 3, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_oam_addr_reset_while_rendering() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_oam_addr(0x42);
+        ppu.tick(255);
+        ppu.tick(3);
+        // 描画していなければそのまま
+        assert_eq!(ppu.oam_addr, 0x42);
+
+        ppu.write_to_mask(0b0001_0000);
+        ppu.tick(255);
+        ppu.tick(86);
+        assert_eq!(ppu.oam_addr, 0);
+    }
+
+    #[test]
+    fn test_mixed_chr_rom_and_ram() {
+        // $0000-$0FFFはCHR-ROM、$1000-$1FFFはCHR-RAM
+        let mapper = TestMapper { chr: vec![0x11; 0x2000], chr_ram_start: 0x1000, ..TestMapper::new() };
+        let mut ppu = NesPPU::with_mapper(Rc::new(RefCell::new(mapper)));
+        for addr in [0x0abcu16, 0x1abc] {
+            ppu.write_to_ppu_addr((addr >> 8) as u8);
+            ppu.write_to_ppu_addr(addr as u8);
+            ppu.write_to_data(0x56);
+        }
+
+        assert_eq!(ppu.read_chr(0x0abc), 0x11);
+        assert_eq!(ppu.read_chr(0x1abc), 0x56);
     }
 }
@@ -1,3 +1,5 @@
+use crate::savestate::{StateReader, StateWriter};
+
 pub struct AddrRegister {
     value: (u8, u8),
     hi_ptr: bool,
@@ -44,7 +46,22 @@ impl AddrRegister {
         self.hi_ptr = true;
     }
 
+    pub fn flip_latch(&mut self) {
+        self.hi_ptr = !self.hi_ptr;
+    }
+
     pub fn get(&self) -> u16 {
         ((self.value.0 as u16) << 8) | (self.value.1 as u16)
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.get());
+        w.write_bool(self.hi_ptr);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.set(r.read_u16()?);
+        self.hi_ptr = r.read_bool()?;
+        Ok(())
+    }
 }
@@ -92,4 +92,9 @@ impl ControlRegister {
     pub fn update(&mut self, data: u8) {
         self.bits = data;
     }
+
+    // $2006の1回目の書き込みもネームテーブルのビットを書き換える
+    pub fn set_nametable(&mut self, index: u8) {
+        self.bits = (self.bits & !0b11) | (index & 0b11);
+    }
 }
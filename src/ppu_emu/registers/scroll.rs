@@ -22,6 +22,23 @@ impl ScrollRegister {
         self.latch = !self.latch;
     }
 
+    // $2006はスクロールと同じtレジスタを書き換えるので、スクロール位置も変わる
+    // 1回目はtのbit8-13 (粗いYの上位2ビット、ネームテーブル、細かいYの下位2ビット。細かいYのbit2は0になる)
+    // 2回目はtのbit0-7 (粗いX、粗いYの下位3ビット)。細かいXはそのまま
+    // ネームテーブルのビットはControlRegisterの方で持っている
+    pub fn write_ppu_addr(&mut self, data: u8) {
+        if !self.latch {
+            let coarse_y = (self.scroll_y >> 3) & 0b00111 | (data & 0b11) << 3;
+            let fine_y = (data >> 4) & 0b11;
+            self.scroll_y = coarse_y << 3 | fine_y;
+        } else {
+            let coarse_y = (self.scroll_y >> 3) & 0b11000 | data >> 5;
+            self.scroll_y = coarse_y << 3 | (self.scroll_y & 0b111);
+            self.scroll_x = (data & 0b11111) << 3 | (self.scroll_x & 0b111);
+        }
+        self.latch = !self.latch;
+    }
+
     pub fn reset_latch(&mut self) {
         self.latch = false;
     }
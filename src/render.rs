@@ -1,2 +1,6 @@
+pub mod debug;
 pub mod frame;
 pub mod palette;
+pub mod scale;
+
+pub use debug::{draw_debug_grid, GridKind};
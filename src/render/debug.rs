@@ -0,0 +1,70 @@
+use crate::render::frame::Frame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridKind {
+    // 8x8のタイルの境界
+    Tile,
+    // 16x16の属性(パレット)の境界
+    Attribute,
+}
+
+impl GridKind {
+    fn size(&self) -> usize {
+        match self {
+            GridKind::Tile => 8,
+            GridKind::Attribute => 16,
+        }
+    }
+
+    fn color(&self) -> (u8, u8, u8) {
+        match self {
+            GridKind::Tile => (0xff, 0x00, 0xff),
+            GridKind::Attribute => (0x00, 0xff, 0xff),
+        }
+    }
+}
+
+// 描画済みのフレームに境界線を1ピクセル幅で上書きする (デバッグ用)
+pub fn draw_debug_grid(frame: &mut Frame, grid: GridKind) {
+    let size = grid.size();
+    for y in 0..Frame::HIGHT {
+        for x in 0..Frame::WIDTH {
+            if x % size == 0 || y % size == 0 {
+                frame.set_pixel(x, y, grid.color());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        frame.get_pixel(x, y)
+    }
+
+    #[test]
+    fn test_tile_grid() {
+        let mut frame = Frame::new();
+        draw_debug_grid(&mut frame, GridKind::Tile);
+
+        let line = GridKind::Tile.color();
+        for i in (0..Frame::HIGHT).step_by(8) {
+            assert_eq!(pixel(&frame, i, 3), line);
+            assert_eq!(pixel(&frame, 3, i), line);
+        }
+        for (x, y) in [(1, 1), (7, 7), (9, 15), (100, 101)] {
+            assert_eq!(pixel(&frame, x, y), (0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn test_attribute_grid() {
+        let mut frame = Frame::new();
+        draw_debug_grid(&mut frame, GridKind::Attribute);
+
+        assert_eq!(pixel(&frame, 16, 5), GridKind::Attribute.color());
+        assert_eq!(pixel(&frame, 8, 5), (0, 0, 0));
+    }
+}
@@ -1,23 +1,236 @@
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    fn hides(&self, x: usize, y: usize) -> bool {
+        y < self.top || y + self.bottom >= Frame::HIGHT || x < self.left || x + self.right >= Frame::WIDTH
+    }
+}
+
+// Frame::diffで変わった部分を囲む長方形 (ピクセル単位)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
 pub struct Frame {
     pub data: Vec<u8>,
+    pub overscan: Overscan,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HIGHT: usize = 240;
 
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            overscan: Overscan::default(),
         }
     }
 
+    // ブラウン管で隠れていた端の部分を黒のままにする
+    pub fn with_overscan(top: usize, bottom: usize, left: usize, right: usize) -> Self {
+        let mut frame = Frame::new();
+        frame.overscan = Overscan { top, bottom, left, right };
+        frame
+    }
+
+    // 画面外の座標は隣の行に回り込ませずpanicする
+    fn index(x: usize, y: usize) -> usize {
+        assert!(
+            x < Frame::WIDTH && y < Frame::HIGHT,
+            "pixel ({}, {}) is outside the {}x{} frame",
+            x, y, Frame::WIDTH, Frame::HIGHT
+        );
+        (y * Frame::WIDTH + x) * 3
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = Frame::index(x, y);
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let base = y * 3 * Frame::WIDTH + x * 3;
-        if base + 2 < self.data.len() {
-            self.data[base] = rgb.0;
-            self.data[base + 1] = rgb.1;
-            self.data[base + 2] = rgb.2;
+        let base = Frame::index(x, y);
+        if self.overscan.hides(x, y) {
+            return;
         }
+        self.data[base] = rgb.0;
+        self.data[base + 1] = rgb.1;
+        self.data[base + 2] = rgb.2;
+    }
+
+    // 全体をcolorで塗る。オーバースキャンの部分は黒のまま
+    pub fn clear(&mut self, color: (u8, u8, u8)) {
+        let overscan = self.overscan;
+        for (y, row) in self.data.chunks_exact_mut(Frame::WIDTH * 3).enumerate() {
+            for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                let rgb = if overscan.hides(x, y) { (0, 0, 0) } else { color };
+                pixel.copy_from_slice(&[rgb.0, rgb.1, rgb.2]);
+            }
+        }
+    }
+
+    // otherと違うピクセルを囲む長方形。変わった行が続いている範囲ごとに1つ返す
+    // フロントエンドはここだけテクスチャを更新すればよい
+    pub fn diff(&self, other: &Frame) -> Vec<DirtyRect> {
+        let mut rects: Vec<DirtyRect> = Vec::new();
+        let mut band: Option<DirtyRect> = None;
+        for y in 0..Frame::HIGHT {
+            let changed = (0..Frame::WIDTH).filter(|&x| self.get_pixel(x, y) != other.get_pixel(x, y));
+            let (first, last) = changed.fold((None, 0), |(first, _), x| (first.or(Some(x)), x));
+            match (first, band.as_mut()) {
+                (Some(first), Some(rect)) => {
+                    let right = (rect.x + rect.width).max(last + 1);
+                    rect.x = rect.x.min(first);
+                    rect.width = right - rect.x;
+                    rect.height += 1;
+                }
+                (Some(first), None) => band = Some(DirtyRect { x: first, y, width: last + 1 - first, height: 1 }),
+                (None, _) => rects.extend(band.take()),
+            }
+        }
+        rects.extend(band);
+        rects
+    }
+
+    // 画面全体のFNV-1a (64bit)。実行環境によらず同じ値になるので、回帰テストで記録した値と比べられる
+    pub fn hash(&self) -> u64 {
+        self.data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fill(frame: &mut Frame, rgb: (u8, u8, u8)) {
+        for y in 0..Frame::HIGHT {
+            for x in 0..Frame::WIDTH {
+                frame.set_pixel(x, y, rgb);
+            }
+        }
+    }
+
+    fn row(frame: &Frame, y: usize) -> &[u8] {
+        &frame.data[y * Frame::WIDTH * 3..(y + 1) * Frame::WIDTH * 3]
+    }
+
+    #[test]
+    fn test_overscan_blanks_top_and_bottom() {
+        let mut frame = Frame::with_overscan(8, 8, 0, 0);
+        fill(&mut frame, (1, 2, 3));
+
+        for y in (0..8).chain(232..240) {
+            assert!(row(&frame, y).iter().all(|b| *b == 0), "row {} not blank", y);
+        }
+        for y in 8..232 {
+            assert_eq!(row(&frame, y), [1, 2, 3].repeat(Frame::WIDTH).as_slice());
+        }
+    }
+
+    #[test]
+    fn test_overscan_blanks_left_and_right() {
+        let mut frame = Frame::with_overscan(0, 0, 8, 4);
+        fill(&mut frame, (9, 9, 9));
+
+        let line = row(&frame, 100);
+        assert!(line[..8 * 3].iter().all(|b| *b == 0));
+        assert!(line[8 * 3..252 * 3].iter().all(|b| *b == 9));
+        assert!(line[252 * 3..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut frame = Frame::new();
+        assert_eq!(frame.hash(), 0x96d6_3225_ea92_6325);
+
+        for y in 0..Frame::HIGHT {
+            for x in 0..Frame::WIDTH {
+                frame.set_pixel(x, y, (x as u8, y as u8, (x ^ y) as u8));
+            }
+        }
+        assert_eq!(frame.hash(), 0x7958_1ea7_e3c7_d325);
+    }
+
+    #[test]
+    fn test_get_and_set_pixel_at_corners() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        frame.set_pixel(255, 239, (4, 5, 6));
+
+        assert_eq!(frame.get_pixel(0, 0), (1, 2, 3));
+        assert_eq!(frame.get_pixel(255, 239), (4, 5, 6));
+        assert_eq!(frame.get_pixel(1, 0), (0, 0, 0));
+        assert_eq!(&frame.data[frame.data.len() - 3..], [4, 5, 6]);
+    }
+
+    #[test]
+    #[should_panic(expected = "pixel (256, 0) is outside the 256x240 frame")]
+    fn test_set_pixel_out_of_range() {
+        Frame::new().set_pixel(256, 0, (1, 2, 3));
+    }
+
+    #[test]
+    #[should_panic(expected = "pixel (0, 240) is outside the 256x240 frame")]
+    fn test_get_pixel_out_of_range() {
+        Frame::new().get_pixel(0, 240);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut frame = Frame::new();
+        frame.set_pixel(10, 10, (1, 2, 3));
+        frame.clear((0x20, 0x40, 0x60));
+        assert!(frame.data.chunks(3).all(|p| p == [0x20, 0x40, 0x60]));
+
+        let mut frame = Frame::with_overscan(8, 0, 0, 0);
+        frame.clear((7, 7, 7));
+        assert_eq!(frame.get_pixel(0, 7), (0, 0, 0));
+        assert_eq!(frame.get_pixel(0, 8), (7, 7, 7));
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = Frame::new();
+        let mut after = Frame::new();
+        assert_eq!(before.diff(&after), vec![]);
+
+        for y in 100..108 {
+            for x in 40..56 {
+                after.set_pixel(x, y, (1, 2, 3));
+            }
+        }
+        assert_eq!(before.diff(&after), vec![DirtyRect { x: 40, y: 100, width: 16, height: 8 }]);
+
+        // 離れた行は別の長方形になる
+        after.set_pixel(255, 239, (9, 9, 9));
+        after.set_pixel(0, 0, (9, 9, 9));
+        assert_eq!(
+            after.diff(&before),
+            vec![
+                DirtyRect { x: 0, y: 0, width: 1, height: 1 },
+                DirtyRect { x: 40, y: 100, width: 16, height: 8 },
+                DirtyRect { x: 255, y: 239, width: 1, height: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_overscan_by_default() {
+        let mut frame = Frame::new();
+        fill(&mut frame, (4, 5, 6));
+        assert!(frame.data.chunks(3).all(|p| p == [4, 5, 6]));
     }
 }
@@ -15,3 +15,109 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// 2C02のコンポジット映像信号から64色を計算するときの調整値
+// https://www.nesdev.org/wiki/NTSC_video
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteParams {
+    // 色相を回す角度 (度)
+    pub hue: f32,
+    // 0.0なら白黒
+    pub saturation: f32,
+    pub brightness: f32,
+}
+
+impl Default for PaletteParams {
+    fn default() -> Self {
+        PaletteParams { hue: 0.0, saturation: 1.5, brightness: 1.0 }
+    }
+}
+
+// 信号の電圧 (同期信号を基準)。前半が低い側、後半が高い側で、輝度(色番号の上位2ビット)ごとに4段階
+const SIGNAL_LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+// テレビ側のガンマ (2.2) に対して信号が想定しているガンマ (1.8)
+const GAMMA: f32 = 2.2 / 1.8;
+// 色番号1が青になるように復調の位相をずらす (約117度)
+const HUE_OFFSET: f32 = 3.9;
+
+// 1画素の12クロックのあいだ、色相に応じた位相で2つの電圧を行き来する矩形波をYIQに復調する
+pub fn generate_palette(params: &PaletteParams) -> [(u8, u8, u8); 64] {
+    let mut palette = [(0, 0, 0); 64];
+    for (index, rgb) in palette.iter_mut().enumerate() {
+        let color = index & 0x0f;
+        let level = index >> 4;
+        // $xE/$xFは信号なし(黒)、$x0は低い側も高い側の電圧、$xDは高い側も低い側の電圧
+        let (low, high) = if color >= 0x0e {
+            (BLACK, BLACK)
+        } else {
+            (
+                SIGNAL_LEVELS[level + 4 * (color == 0x00) as usize],
+                SIGNAL_LEVELS[level + 4 * (color < 0x0d) as usize],
+            )
+        };
+
+        let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+        for phase in 0..12 {
+            let in_color_phase = (color + phase) % 12 < 6;
+            let spot = if in_color_phase { high } else { low };
+            let v = (spot - BLACK) / (WHITE - BLACK) * params.brightness / 12.0;
+            let angle = std::f32::consts::PI / 6.0 * (phase as f32 + HUE_OFFSET + params.hue / 30.0);
+            y += v;
+            i += v * angle.cos();
+            q += v * angle.sin();
+        }
+        i *= params.saturation;
+        q *= params.saturation;
+
+        let channel = |value: f32| {
+            let corrected = if value <= 0.0 { 0.0 } else { value.powf(GAMMA) };
+            (255.95 * corrected).clamp(0.0, 255.0) as u8
+        };
+        *rgb = (
+            channel(y + 0.946882 * i + 0.623557 * q),
+            channel(y - 0.274788 * i - 0.635691 * q),
+            channel(y - 1.108545 * i + 1.709007 * q),
+        );
+    }
+    palette
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_near(actual: (u8, u8, u8), expected: (u8, u8, u8)) {
+        let near = |a: u8, b: u8| (a as i16 - b as i16).abs() <= 4;
+        assert!(
+            near(actual.0, expected.0) && near(actual.1, expected.1) && near(actual.2, expected.2),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_generate_default_palette() {
+        let palette = generate_palette(&PaletteParams::default());
+        // 灰色の列と黒
+        assert_near(palette[0x00], (0x53, 0x53, 0x53));
+        assert_near(palette[0x10], (0xa0, 0xa0, 0xa0));
+        assert_near(palette[0x20], (0xff, 0xff, 0xff));
+        assert_near(palette[0x30], (0xff, 0xff, 0xff));
+        assert_eq!(palette[0x0f], (0, 0, 0));
+        assert_eq!(palette[0x1d], (0, 0, 0));
+        // 青・赤・緑
+        assert_near(palette[0x02], (0x10, 0x08, 0x95));
+        assert_near(palette[0x16], (0xa1, 0x27, 0x0b));
+        assert_near(palette[0x1a], (0x05, 0x80, 0x00));
+    }
+
+    #[test]
+    fn test_saturation_zero_is_grayscale() {
+        let palette = generate_palette(&PaletteParams { saturation: 0.0, ..PaletteParams::default() });
+        assert!(palette.iter().all(|(r, g, b)| r == g && g == b));
+        assert_eq!(palette[0x16].0, palette[0x1a].0);
+    }
+}
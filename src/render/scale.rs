@@ -0,0 +1,91 @@
+const NES_WIDTH: f32 = 256.0;
+const NES_HEIGHT: f32 = 240.0;
+// NTSCのピクセルアスペクト比は8:7
+const PIXEL_ASPECT_RATIO: f32 = 8.0 / 7.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleMode {
+    Integer,
+    Fit,
+    AspectCorrect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub scale_x: f32,
+    pub scale_y: f32,
+}
+
+pub fn viewport(mode: ScaleMode, window_width: u32, window_height: u32) -> Viewport {
+    let (w, h) = (window_width as f32, window_height as f32);
+    let (scale_x, scale_y) = match mode {
+        ScaleMode::Integer => {
+            let scale = (w / NES_WIDTH).min(h / NES_HEIGHT).floor().max(1.0);
+            (scale, scale)
+        },
+        ScaleMode::Fit => {
+            let scale = (w / NES_WIDTH).min(h / NES_HEIGHT);
+            (scale, scale)
+        },
+        ScaleMode::AspectCorrect => {
+            let scale = (w / (NES_WIDTH * PIXEL_ASPECT_RATIO)).min(h / NES_HEIGHT);
+            (scale * PIXEL_ASPECT_RATIO, scale)
+        },
+    };
+
+    let width = (NES_WIDTH * scale_x) as u32;
+    let height = (NES_HEIGHT * scale_y) as u32;
+    Viewport {
+        x: (window_width as i32 - width as i32) / 2,
+        y: (window_height as i32 - height as i32) / 2,
+        width,
+        height,
+        scale_x,
+        scale_y,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_integer_exact_fit() {
+        let vp = viewport(ScaleMode::Integer, 768, 720);
+        assert_eq!((vp.x, vp.y, vp.width, vp.height), (0, 0, 768, 720));
+        assert_eq!(vp.scale_x, 3.0);
+    }
+
+    #[test]
+    fn test_integer_centers_in_larger_window() {
+        let vp = viewport(ScaleMode::Integer, 800, 600);
+        assert_eq!((vp.x, vp.y, vp.width, vp.height), (144, 60, 512, 480));
+        assert_eq!((vp.scale_x, vp.scale_y), (2.0, 2.0));
+    }
+
+    #[test]
+    fn test_integer_never_below_one() {
+        let vp = viewport(ScaleMode::Integer, 200, 200);
+        assert_eq!((vp.width, vp.height), (256, 240));
+        assert_eq!((vp.x, vp.y), (-28, -20));
+    }
+
+    #[test]
+    fn test_fit() {
+        let vp = viewport(ScaleMode::Fit, 800, 600);
+        assert_eq!((vp.x, vp.y, vp.width, vp.height), (80, 0, 640, 600));
+        assert_eq!(vp.scale_x, 2.5);
+    }
+
+    #[test]
+    fn test_aspect_correct() {
+        let vp = viewport(ScaleMode::AspectCorrect, 800, 600);
+        assert_eq!((vp.x, vp.y, vp.width, vp.height), (34, 0, 731, 600));
+        assert_eq!(vp.scale_y, 2.5);
+        assert!((vp.scale_x / vp.scale_y - 8.0 / 7.0).abs() < 1e-6);
+    }
+}
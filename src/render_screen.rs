@@ -1,7 +1,6 @@
 use crate::render::frame::Frame;
 use crate::render::palette::SYSTEM_PALLETE;
 use crate::ppu_emu::ppu::NesPPU;
-use crate::emu::cartridge::Mirroring;
 
 struct Rect {
     x1: usize,
@@ -21,7 +20,25 @@ impl Rect {
     }
 }
 
-fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8], view_port: Rect, shift_x: isize, shift_y: isize) {
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+
+// 背景の1ピクセル。valueはパターンの2ビット(0なら透明)、colorはパレットRAMの位置
+// (ラインの途中でパレットを書き換えるゲームがあるので、色は最後にラインごとのパレットで決める)
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BgPixel {
+    value: u8,
+    color: u8,
+}
+
+// 一番手前(OAMの番号が小さい)スプライトの不透明なピクセル。colorはパレットRAMの位置
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SpritePixel {
+    color: u8,
+    behind_background: bool,
+}
+
+fn render_name_table(ppu: &NesPPU, bg: &mut [BgPixel], name_table: &[u8], view_port: Rect, shift_x: isize, shift_y: isize) {
     let bank = ppu.ctrl.bknd_pattern_addr();
 
     let attribute_table = &name_table[0x3c0..0x400];
@@ -30,36 +47,33 @@ fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8], view_po
         let tile_column = i % 32;
         let tile_row = i / 32;
         let tile_idx = name_table[i] as u16;
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
+        let tile = ppu.chr_tile(bank + tile_idx * 16);
+        let palette = bg_pallette(attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
             let mut upper = tile[y];
             let mut lower = tile[y + 8];
 
             for x in (0..=7).rev() {
-                let value = (1 & upper) << 1 | (1 & lower);
+                // 先頭8バイトが下位ビット、後ろ8バイトが上位ビット
+                let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
-                let rgb = match value {
-                    0 => SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => SYSTEM_PALLETE[palette[1] as usize],
-                    2 => SYSTEM_PALLETE[palette[2] as usize],
-                    3 => SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
                 let pixel_x = tile_column * 8 + x;
                 let pixel_y = tile_row * 8 + y;
 
                 if pixel_x >= view_port.x1 && pixel_x < view_port.x2 && pixel_y >= view_port.y1 && pixel_y < view_port.y2 {
-                    frame.set_pixel((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, rgb)
+                    let (screen_x, screen_y) = ((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize);
+                    if screen_x < WIDTH && screen_y < HEIGHT {
+                        bg[screen_y * WIDTH + screen_x] = BgPixel { value, color: palette[value as usize] };
+                    }
                 }
             }
         }
     }
 }
 
-fn bg_pallette(ppu: &NesPPU, attribute_table: &[u8], tile_column: usize, tile_row : usize) -> [u8;4] {
+fn bg_pallette(attribute_table: &[u8], tile_column: usize, tile_row : usize) -> [u8;4] {
     let attr_table_idx = tile_row / 4 * 8 +  tile_column / 4;
     let attr_byte = attribute_table[attr_table_idx];
 
@@ -71,83 +85,387 @@ fn bg_pallette(ppu: &NesPPU, attribute_table: &[u8], tile_column: usize, tile_ro
         (_,_) => panic!("should not happen"),
     };
 
-    let pallete_start: usize = 1 + (pallet_idx as usize)*4;
-    [ppu.palette_table[0], ppu.palette_table[pallete_start], ppu.palette_table[pallete_start+1], ppu.palette_table[pallete_start+2]]
+    let pallete_start = 1 + pallet_idx * 4;
+    [0, pallete_start, pallete_start + 1, pallete_start + 2]
 }
 
-fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
-    let start = 0x11 + (pallete_idx * 4) as usize;
-    [
-        0,
-        ppu.palette_table[start],
-        ppu.palette_table[start + 1],
-        ppu.palette_table[start + 2],
-    ]
+fn sprite_palette(pallete_idx: u8) -> [u8; 4] {
+    let start = 0x11 + pallete_idx * 4;
+    [0, start, start + 1, start + 2]
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
-    let scroll_x = (ppu.scroll.scroll_x) as usize;
-    let scroll_y = (ppu.scroll.scroll_y) as usize;
-
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2000) | (Mirroring::HORIZONTAL, 0x2400) => {
-            (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
-        },
-        (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2c00) | (Mirroring::HORIZONTAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2C00) => {
-            (&ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
-        },
-        (_, _) => panic!("Not supported mirroring type {:?}", ppu.mirroring)
-    };
-
-    render_name_table(ppu, frame, main_nametable, Rect::new(scroll_x, scroll_y, 256, 240), -(scroll_x as isize), -(scroll_y as isize));
-    if scroll_x > 0 {
-        render_name_table(ppu, frame, second_nametable, Rect::new(0, 0, scroll_x, 240), (256 - scroll_x) as isize, 0);
-    } else if scroll_y > 0 {
-        render_name_table(ppu, frame, second_nametable, Rect::new(0, 0, 256, scroll_y), 0, (240 - scroll_y) as isize);
-    }
+fn render_sprites(ppu: &NesPPU) -> Vec<Option<SpritePixel>> {
+    let mut sprites = vec![None; WIDTH * HEIGHT];
+    let sprites_per_line: Vec<Vec<usize>> = (0..HEIGHT).map(|y| ppu.sprites_on_scanline(y)).collect();
+    let bank: u16 = ppu.ctrl.sprt_pattern_addr();
 
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
+    // OAMの番号が小さいほど手前。既に埋まったピクセルには描かない
+    for i in (0..ppu.oam_data.len()).step_by(4) {
         let tile_idx = ppu.oam_data[i + 1] as u16;
         let tile_x = ppu.oam_data[i + 3] as usize;
         let tile_y = ppu.oam_data[i] as usize;
-        let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-        let flip_horizontal = if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-
-        let pallete_idx = ppu.oam_data[i + 2] & 0b11;
-        let sprite_palette = sprite_palette(ppu, pallete_idx);
-        let bank: u16 = ppu.ctrl.sprt_pattern_addr();
-
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let attributes = ppu.oam_data[i + 2];
+        let flip_vertical = attributes >> 7 & 1 == 1;
+        let flip_horizontal = attributes >> 6 & 1 == 1;
+        let behind_background = attributes >> 5 & 1 == 1;
+
+        let sprite_palette = sprite_palette(attributes & 0b11);
+        let tile = ppu.chr_tile(bank + tile_idx * 16);
 
         for y in 0..=7 {
+            let pixel_y = if flip_vertical { tile_y + 7 - y } else { tile_y + y };
+            // 1ラインのスプライト数の制限で落ちた行は描かない
+            if pixel_y >= HEIGHT || !sprites_per_line[pixel_y].contains(&(i / 4)) {
+                continue;
+            }
+
             let mut upper = tile[y];
             let mut lower = tile[y + 8];
-            'ololo: for x in (0..=7).rev() {
+            for x in (0..=7).rev() {
                 let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue 'ololo, // skip coloring the pixel
-                    1 => SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => SYSTEM_PALLETE[sprite_palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+                let pixel_x = if flip_horizontal { tile_x + 7 - x } else { tile_x + x };
+                // 色番号0は透明
+                if value == 0 || pixel_x >= WIDTH {
+                    continue;
+                }
+                let pixel = &mut sprites[pixel_y * WIDTH + pixel_x];
+                if pixel.is_none() {
+                    *pixel = Some(SpritePixel { color: sprite_palette[value as usize], behind_background });
                 }
             }
         }
     }
+    sprites
+}
+
+// パターンテーブル$0000と$1000を128x128ずつ左右に並べて描く (デバッグ用)
+// paletteは0-3が背景、4-7がスプライトのパレット
+pub fn render_pattern_table(ppu: &NesPPU, frame: &mut Frame, palette: u8) {
+    let start = palette as usize * 4;
+    let colors = [
+        ppu.palette_table[0],
+        ppu.palette_table[start + 1],
+        ppu.palette_table[start + 2],
+        ppu.palette_table[start + 3],
+    ];
+
+    for tile_n in 0..512 {
+        let tile = ppu.chr_tile(tile_n as u16 * 16);
+        let tile_x = (tile_n / 256) * 128 + (tile_n % 16) * 8;
+        let tile_y = (tile_n % 256) / 16 * 8;
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let value = (tile[y + 8] >> (7 - x) & 1) << 1 | (tile[y] >> (7 - x) & 1);
+                frame.set_pixel(tile_x + x, tile_y + y, SYSTEM_PALLETE[colors[value as usize] as usize]);
+            }
+        }
+    }
+}
+
+// https://www.nesdev.org/wiki/PPU_rendering#Preface
+// 背景が透明ならスプライト、スプライトが背景より奥なら不透明な背景が勝つ
+fn multiplex(bg: BgPixel, sprite: Option<SpritePixel>) -> u8 {
+    match sprite {
+        Some(sprite) if bg.value == 0 || !sprite.behind_background => sprite.color,
+        _ => bg.color,
+    }
+}
+
+pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+    render_with_palette(ppu, frame, &SYSTEM_PALLETE);
+}
+
+// system_paletteは64色のRGB。palette::generate_paletteで作ったものも使える
+pub fn render_with_palette(ppu: &NesPPU, frame: &mut Frame, system_palette: &[(u8, u8, u8); 64]) {
+    let scroll_x = (ppu.scroll.scroll_x) as usize;
+    let scroll_y = (ppu.scroll.scroll_y) as usize;
+
+    // 横にスクロールしていれば右隣、縦なら下のネームテーブルが続きになる
+    // (どれが同じ実体になるかはミラーリングで決まる)
+    let nametable_addr = ppu.ctrl.nametable_addr();
+    let main_nametable = ppu.nametable(nametable_addr);
+    let second_nametable = if scroll_x > 0 {
+        ppu.nametable(nametable_addr ^ 0x400)
+    } else {
+        ppu.nametable(nametable_addr ^ 0x800)
+    };
+
+    // 隠したレイヤーは背景色と透明のままにする
+    let mut bg = vec![BgPixel { value: 0, color: 0 }; WIDTH * HEIGHT];
+    if ppu.show_background() {
+        render_name_table(ppu, &mut bg, main_nametable, Rect::new(scroll_x, scroll_y, 256, 240), -(scroll_x as isize), -(scroll_y as isize));
+        if scroll_x > 0 {
+            render_name_table(ppu, &mut bg, second_nametable, Rect::new(0, 0, scroll_x, 240), (256 - scroll_x) as isize, 0);
+        } else if scroll_y > 0 {
+            render_name_table(ppu, &mut bg, second_nametable, Rect::new(0, 0, 256, scroll_y), 0, (240 - scroll_y) as isize);
+        }
+    }
+
+    let sprites = if ppu.show_sprites() { render_sprites(ppu) } else { vec![None; WIDTH * HEIGHT] };
+
+    // 全ピクセルを描き直すので、前のフレームの色は残らない
+    for y in 0..HEIGHT {
+        let palette = ppu.palette_for_scanline(y);
+        for x in 0..WIDTH {
+            let i = y * WIDTH + x;
+            let color = palette[multiplex(bg[i], sprites[i]) as usize];
+            frame.set_pixel(x, y, system_palette[color as usize]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emu::cartridge::Mirroring;
+    use crate::ppu_emu::ppu::PPU;
+
+    // 4つのパレットを区別できるように、それぞれの色番号3に別の色を入れておく
+    fn quadrant_test_ppu(attr_byte: u8) -> NesPPU {
+        let mut chr_rom = vec![0; 0x2000];
+        for b in chr_rom[0..16].iter_mut() {
+            *b = 0xff;
+        }
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.vram[0x3c0] = attr_byte;
+        ppu.palette_table[3] = 0x01;
+        ppu.palette_table[7] = 0x16;
+        ppu.palette_table[11] = 0x2a;
+        ppu.palette_table[15] = 0x30;
+        ppu.oam_data = [0xff; 256];
+        ppu
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        frame.get_pixel(x, y)
+    }
+
+    #[test]
+    fn test_bg_palette_quadrants() {
+        let ppu = quadrant_test_ppu(0b11_10_01_00);
+        let attribute_table = &ppu.vram[0x3c0..0x400];
+
+        // top-left, top-right, bottom-left, bottom-right
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 0, 0)[3] as usize], 0x01);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 1, 1)[3] as usize], 0x01);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 2, 0)[3] as usize], 0x16);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 3, 1)[3] as usize], 0x16);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 0, 2)[3] as usize], 0x2a);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 1, 3)[3] as usize], 0x2a);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 2, 2)[3] as usize], 0x30);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 3, 3)[3] as usize], 0x30);
+    }
+
+    #[test]
+    fn test_bg_palette_uses_next_attribute_byte() {
+        let mut ppu = quadrant_test_ppu(0);
+        ppu.vram[0x3c1] = 0b01;
+        ppu.vram[0x3c8] = 0b10;
+        let attribute_table = &ppu.vram[0x3c0..0x400];
+
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 3, 3)[3] as usize], 0x01);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 4, 0)[3] as usize], 0x16);
+        assert_eq!(ppu.palette_table[bg_pallette(attribute_table, 0, 4)[3] as usize], 0x2a);
+    }
+
+    // 同じラインに10個のスプライトを横に並べる
+    fn ten_sprites_on_a_line(no_sprite_limit: bool) -> Frame {
+        let mut chr_rom = vec![0; 0x2000];
+        for b in chr_rom[16..32].iter_mut() {
+            *b = 0xff;
+        }
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette_table[0x13] = 0x16;
+        ppu.oam_data = [0xff; 256];
+        for i in 0..10 {
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[50, 1, 0, (i * 10) as u8]);
+        }
+        ppu.no_sprite_limit = no_sprite_limit;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        frame
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let frame = ten_sprites_on_a_line(false);
+        let sprite = SYSTEM_PALLETE[0x16];
+        for i in 0..8 {
+            assert_eq!(pixel(&frame, i * 10, 50), sprite);
+        }
+        assert_ne!(pixel(&frame, 80, 50), sprite);
+        assert_ne!(pixel(&frame, 90, 50), sprite);
+    }
+
+    #[test]
+    fn test_no_sprite_limit() {
+        let frame = ten_sprites_on_a_line(true);
+        for i in 0..10 {
+            assert_eq!(pixel(&frame, i * 10, 50), SYSTEM_PALLETE[0x16]);
+        }
+    }
+
+    fn overlapping_sprite_ppu(attributes: u8) -> NesPPU {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16..24].copy_from_slice(&[0xf0; 8]);
+        chr_rom[32..48].copy_from_slice(&[0xff; 16]);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.vram[32 + 1] = 1;
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[0x13] = 0x2a;
+        ppu.oam_data = [0xff; 256];
+        ppu.oam_data[0..4].copy_from_slice(&[8, 2, attributes, 10]);
+        ppu
+    }
+
+    // 背景のタイル(左半分だけ不透明)に、スプライトを右に2ピクセルずらして重ねる
+    fn overlapping_sprite(attributes: u8) -> Frame {
+        let mut frame = Frame::new();
+        render(&overlapping_sprite_ppu(attributes), &mut frame);
+        frame
+    }
+
+    #[test]
+    fn test_hide_layers() {
+        let (backdrop, bg, sprite) = (SYSTEM_PALLETE[0x0f], SYSTEM_PALLETE[0x16], SYSTEM_PALLETE[0x2a]);
+
+        let mut ppu = overlapping_sprite_ppu(0);
+        ppu.set_show_sprites(false);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert!(frame.data.chunks(3).all(|p| p != [sprite.0, sprite.1, sprite.2]));
+        assert_eq!(pixel(&frame, 10, 8), bg);
+        assert_eq!(pixel(&frame, 12, 8), backdrop);
+
+        ppu.set_show_sprites(true);
+        ppu.set_show_background(false);
+        render(&ppu, &mut frame);
+        assert!(frame.data.chunks(3).all(|p| p != [bg.0, bg.1, bg.2]));
+        assert_eq!(pixel(&frame, 9, 8), backdrop);
+        assert_eq!(pixel(&frame, 10, 8), sprite);
+    }
+
+    #[test]
+    fn test_transparent_pixels_use_backdrop() {
+        let mut ppu = overlapping_sprite_ppu(0);
+        // 左半分だけ不透明なスプライトを何もない所に置く
+        ppu.oam_data[4..8].copy_from_slice(&[39, 1, 0, 40]);
+        ppu.palette_table[0x11] = 0x2a;
+        // $3F10は$3F00のミラーなので、ここに書いても背景色が変わる
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0x21);
+        assert_eq!(ppu.palette_table[0], 0x21);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let backdrop = SYSTEM_PALLETE[0x21];
+        assert_eq!(pixel(&frame, 0, 0), backdrop);
+        // 背景タイルの透明な部分
+        assert_eq!(pixel(&frame, 20, 8), backdrop);
+        // スプライトの透明な部分
+        assert_eq!(pixel(&frame, 43, 40), SYSTEM_PALLETE[0x2a]);
+        assert_eq!(pixel(&frame, 44, 40), backdrop);
+    }
+
+    #[test]
+    fn test_sprite_background_priority_per_pixel() {
+        let (backdrop, bg, sprite) = (SYSTEM_PALLETE[0x0f], SYSTEM_PALLETE[0x16], SYSTEM_PALLETE[0x2a]);
+
+        let front = overlapping_sprite(0);
+        // 背景のタイルは下位ビットの面だけなので色番号1
+        assert_eq!(pixel(&front, 9, 8), bg);
+        assert_eq!(pixel(&front, 10, 8), sprite);
+        assert_eq!(pixel(&front, 11, 8), sprite);
+        assert_eq!(pixel(&front, 12, 8), sprite);
+        assert_eq!(pixel(&front, 17, 8), sprite);
+        assert_eq!(pixel(&front, 18, 8), backdrop);
+
+        // 奥のスプライトは背景の不透明な部分にだけ隠れる
+        let behind = overlapping_sprite(0b0010_0000);
+        assert_eq!(pixel(&behind, 9, 8), bg);
+        assert_eq!(pixel(&behind, 10, 8), bg);
+        assert_eq!(pixel(&behind, 11, 8), bg);
+        assert_eq!(pixel(&behind, 12, 8), sprite);
+        assert_eq!(pixel(&behind, 17, 8), sprite);
+    }
+
+    #[test]
+    fn test_render_pattern_table_uses_palette() {
+        let mut chr_rom = vec![0; 0x2000];
+        // $0000のタイル1は色番号1、$1000のタイル0は色番号3
+        chr_rom[16..24].copy_from_slice(&[0xff; 8]);
+        chr_rom[0x1000..0x1010].copy_from_slice(&[0xff; 16]);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+
+        let mut palette = [0; 32];
+        palette[0] = 0x0f;
+        palette[5] = 0x16;
+        palette[7] = 0x2a;
+        ppu.set_palette(&palette);
+        assert_eq!(ppu.palette(), palette);
+
+        let mut frame = Frame::new();
+        render_pattern_table(&ppu, &mut frame, 1);
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALLETE[0x0f]);
+        assert_eq!(pixel(&frame, 8, 0), SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 15, 7), SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 128, 0), SYSTEM_PALLETE[0x2a]);
+    }
+
+    #[test]
+    fn test_render_attribute_quadrants() {
+        let ppu = quadrant_test_ppu(0b11_10_01_00);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let quadrants = [
+            ((0, 0), 0x01),
+            ((16, 0), 0x16),
+            ((0, 16), 0x2a),
+            ((16, 16), 0x30),
+        ];
+        for ((x, y), color) in quadrants.iter() {
+            let expected = SYSTEM_PALLETE[*color as usize];
+            assert_eq!(pixel(&frame, *x, *y), expected);
+            assert_eq!(pixel(&frame, x + 15, y + 15), expected);
+        }
+    }
+
+    #[test]
+    fn test_palette_write_mid_frame() {
+        // タイル0は全面が色番号1。ネームテーブルはすべてタイル0
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0..8].copy_from_slice(&[0xff; 8]);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette_table[1] = 0x16;
+        ppu.oam_data = [0xff; 256];
+
+        while ppu.scanline() != 100 {
+            ppu.tick(1);
+        }
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x01);
+        ppu.write_to_data(0x2a);
+        // $2006でスクロール位置も変わるので、ゲームと同じく戻しておく
+        ppu.write_to_ctrl(0);
+        ppu.write_to_scroll(0);
+        ppu.write_to_scroll(0);
+        while !(ppu.scanline() == 241 && ppu.cycle() == 1) {
+            ppu.tick(1);
+        }
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 10, 50), SYSTEM_PALLETE[0x16]);
+        // 書き換えたラインまでは前の色
+        assert_eq!(pixel(&frame, 10, 100), SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 10, 101), SYSTEM_PALLETE[0x2a]);
+        assert_eq!(pixel(&frame, 10, 200), SYSTEM_PALLETE[0x2a]);
+    }
 }
@@ -0,0 +1,135 @@
+use crate::emu::cartridge::{Rom, RomError};
+use crate::emu::cpu::{Mem, RunStop};
+use crate::emulator::Emulator;
+use crate::joypad::Joypad;
+
+// blarggのテストROMは$6000に状態を、$6001-$6003に署名を、$6004からメッセージを書く
+// https://github.com/christopherpow/nes-test-roms/blob/master/README
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const MESSAGE: u16 = 0x6004;
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+
+// 60秒分 (長いテストでも大抵これで終わる)
+const MAX_FRAMES: usize = 60 * 60;
+// $81のあとリセットを押すまで100ms以上待つ
+const RESET_DELAY_FRAMES: usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestResult {
+    Passed { message: String },
+    Failed { code: u8, message: String },
+    // 署名が書かれないか、終わらなかった
+    TimedOut,
+    // 結果を書く前にBRKなどでCPUが止まった
+    Stopped(RunStop),
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        matches!(self, TestResult::Passed { .. })
+    }
+}
+
+fn has_signature(emulator: &mut Emulator) -> bool {
+    let cpu = emulator.cpu_mut();
+    (0..3).all(|i| cpu.mem_read(STATUS + 1 + i) == SIGNATURE[i as usize])
+}
+
+fn read_message(emulator: &mut Emulator) -> String {
+    let cpu = emulator.cpu_mut();
+    let mut bytes = vec![];
+    for addr in MESSAGE..=0x7fff {
+        match cpu.mem_read(addr) {
+            0 => break,
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+pub fn run_test_rom(rom: Rom) -> Result<TestResult, RomError> {
+    let mut emulator = Emulator::new(rom)?;
+    let mut joypad = Joypad::new();
+    let mut running = false;
+    let mut reset_at = None;
+
+    for frame in 0..MAX_FRAMES {
+        if let Err(stop) = emulator.step_frame(&mut joypad) {
+            return Ok(TestResult::Stopped(stop));
+        }
+
+        if reset_at == Some(frame) {
+            emulator.reset();
+            reset_at = None;
+        }
+        if !has_signature(&mut emulator) {
+            continue;
+        }
+
+        match emulator.cpu_mut().mem_read(STATUS) {
+            STATUS_RUNNING => running = true,
+            STATUS_NEEDS_RESET if reset_at.is_none() => {
+                reset_at = Some(frame + RESET_DELAY_FRAMES);
+            }
+            0 if running => {
+                return Ok(TestResult::Passed { message: read_message(&mut emulator) });
+            }
+            code if running && code < STATUS_RUNNING => {
+                return Ok(TestResult::Failed { code, message: read_message(&mut emulator) });
+            }
+            _ => {}
+        }
+    }
+    Ok(TestResult::TimedOut)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emu::cartridge::RomBuilder;
+
+    // $80を書いてフレームをまたいでから結果コードとメッセージを書いて止まる
+    fn protocol_rom(code: u8, message: &str) -> Rom {
+        let mut program = vec![];
+        let mut store = |value: u8, addr: u16| {
+            // LDA #value / STA addr
+            program.extend([0xa9, value, 0x8d, addr as u8, (addr >> 8) as u8]);
+        };
+        store(STATUS_RUNNING, STATUS);
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            store(*byte, STATUS + 1 + i as u16);
+        }
+        for (i, byte) in message.bytes().chain([0]).enumerate() {
+            store(byte, MESSAGE + i as u16);
+        }
+        // BIT $2002 / BPL -5 を2回 (VBlankを2回待つ)
+        program.extend([0x2c, 0x02, 0x20, 0x10, 0xfb, 0x2c, 0x02, 0x20, 0x10, 0xfb]);
+        program.extend([0xa9, code, 0x8d, 0x00, 0x60]);
+        let done = 0x8000 + program.len() as u16;
+        // JMP done
+        program.extend([0x4c, done as u8, (done >> 8) as u8]);
+
+        let mut prg = vec![0; 0x8000];
+        prg[0..program.len()].copy_from_slice(&program);
+        prg[0x7ffc] = 0x00;
+        prg[0x7ffd] = 0x80;
+        RomBuilder::new().prg(&prg).build()
+    }
+
+    #[test]
+    fn test_passed() {
+        let result = run_test_rom(protocol_rom(0, "Passed\n")).unwrap();
+        assert_eq!(result, TestResult::Passed { message: "Passed\n".to_string() });
+        assert!(result.passed());
+    }
+
+    #[test]
+    fn test_failed() {
+        let result = run_test_rom(protocol_rom(3, "Failed #3")).unwrap();
+        assert_eq!(result, TestResult::Failed { code: 3, message: "Failed #3".to_string() });
+        assert!(!result.passed());
+    }
+}
@@ -0,0 +1,28 @@
+use nes_emu::emu::cartridge::RomBuilder;
+use nes_emu::emu::cpu::Mem;
+use nes_emu::emulator::Emulator;
+use nes_emu::joypad::Joypad;
+
+// デフォルトのfeature (sdlなし) でコアだけを動かす。SDL2がリンクされていなくても通る
+#[test]
+fn runs_headless_without_sdl() {
+    let mut prg = vec![0; 0x8000];
+    let program = [
+        0xa9, 0x80, // LDA #$80
+        0x8d, 0x00, 0x20, // STA $2000
+        0x4c, 0x05, 0x80, // JMP $8005
+        0xe6, 0x10, // INC $10
+        0x40, // RTI
+    ];
+    prg[0..program.len()].copy_from_slice(&program);
+    prg[0x7ffa..0x8000].copy_from_slice(&[0x08, 0x80, 0x00, 0x80, 0x00, 0x80]);
+
+    let mut emulator = Emulator::new(RomBuilder::new().prg(&prg).build()).unwrap();
+    let mut joypad = Joypad::new();
+    for _ in 0..3 {
+        emulator.step_frame(&mut joypad).unwrap();
+    }
+
+    assert_eq!(emulator.frame_buffer().len(), 256 * 240 * 3);
+    assert_eq!(emulator.cpu_mut().mem_read(0x10), 3);
+}
@@ -0,0 +1,60 @@
+use nes_emu::emu::cartridge::Rom;
+use nes_emu::emu::cpu::Mem;
+use nes_emu::emulator::Emulator;
+use nes_emu::joypad::{Joypad, JoypadButton};
+use std::path::PathBuf;
+
+// tests/roms に同梱したnestestを、決まったフレーム数だけ動かしてRAMや画面を確かめる
+// ROMの足し方は tests/roms/README.md を参照
+struct RomRunner {
+    emulator: Emulator,
+    joypad: Joypad,
+}
+
+impl RomRunner {
+    fn load(name: &str) -> Self {
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "roms", name].iter().collect();
+        let raw = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let rom = Rom::from_bytes(&raw).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let emulator = Emulator::new(rom).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        RomRunner { emulator, joypad: Joypad::new() }
+    }
+
+    fn run_frames(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.emulator.step_frame(&mut self.joypad).unwrap();
+        }
+    }
+
+    // framesのあいだボタンを押して離す
+    fn press(&mut self, button: JoypadButton, frames: usize) {
+        self.joypad.set_button_pressed_status(button, true);
+        self.run_frames(frames);
+        self.joypad.set_button_pressed_status(button, false);
+    }
+
+    fn ram(&mut self, addr: u16) -> u8 {
+        self.emulator.cpu_mut().mem_read(addr)
+    }
+
+    fn frame_buffer(&self) -> Vec<u8> {
+        self.emulator.frame_buffer().to_vec()
+    }
+}
+
+// nestest (kevtris, 自由に配布できる) を通常のリセットから起動し、メニューでSTARTを押して全テストを流す
+// 結果は$02 (公式命令) と $03 (非公式命令) に残り、0なら全部通っている
+#[test]
+fn nestest_menu_runs_all_tests() {
+    let mut runner = RomRunner::load("nestest.nes");
+    runner.run_frames(30);
+    let menu = runner.frame_buffer();
+
+    runner.press(JoypadButton::START, 5);
+    runner.run_frames(120);
+
+    assert_eq!(runner.ram(0x02), 0x00, "official opcode tests failed");
+    assert_eq!(runner.ram(0x03), 0x00, "unofficial opcode tests failed");
+    // 結果が画面に出ている
+    assert_ne!(runner.frame_buffer(), menu);
+}
@@ -0,0 +1,29 @@
+use nes_emu::emu::cartridge::Rom;
+use nes_emu::test_rom::{run_test_rom, TestResult};
+use std::path::PathBuf;
+
+// テストROMは同梱していないので、NES_TEST_ROMSにディレクトリを指定して
+// `cargo test -- --ignored` で実行する
+fn run(name: &str) {
+    let dir = std::env::var("NES_TEST_ROMS").expect("set NES_TEST_ROMS to the nes-test-roms directory");
+    let path: PathBuf = [dir.as_str(), name].iter().collect();
+    let raw = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let rom = Rom::new(&raw).unwrap();
+
+    match run_test_rom(rom) {
+        Ok(TestResult::Passed { .. }) => {}
+        result => panic!("{}: {:?}", name, result),
+    }
+}
+
+#[test]
+#[ignore]
+fn instr_test_v5_official_only() {
+    run("instr_test-v5/official_only.nes");
+}
+
+#[test]
+#[ignore]
+fn ppu_vbl_nmi() {
+    run("ppu_vbl_nmi/ppu_vbl_nmi.nes");
+}